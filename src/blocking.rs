@@ -0,0 +1,192 @@
+//! Blocking wrapper of a [`SafeBox`], for programs that are not async.
+//!
+//! ```no_run
+//! # #[cfg(not(feature = "postgres"))]
+//! # fn example() -> Result<(), safe_box::Error> {
+//! use safe_box::blocking::BlockingSafeBox;
+//!
+//! let safe = BlockingSafeBox::new("secure.db")?;
+//! safe.create("Alice", "Alice's password")?;
+//! let token = safe.verify("Alice", "Alice's password")?;
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(not(feature = "postgres"))]
+use std::path::Path;
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{Error, LoginContext, SafeBox, Token, VerifyOutcome};
+
+/// A [`SafeBox`] whose operations block until they complete,
+/// running them on an internal single-threaded tokio runtime.
+///
+/// It must not be used from within an async runtime.
+pub struct BlockingSafeBox {
+    // dropped before the runtime its connections belong to
+    safe: Option<SafeBox>,
+    rt: Runtime,
+}
+
+impl BlockingSafeBox {
+    /// Open the SQLite database file at `p` like [`SafeBox::new`].
+    #[cfg(not(feature = "postgres"))]
+    pub fn new(p: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open(SafeBox::new(p))
+    }
+
+    /// Connect to the database at `url` like [`SafeBox::connect`].
+    pub fn connect(url: &str) -> Result<Self, Error> {
+        Self::open(SafeBox::connect(url))
+    }
+
+    /// Wait for `safe` to be opened, such as by a [`SafeBoxBuilder`](crate::SafeBoxBuilder):
+    /// ```no_run
+    /// # fn example() -> Result<(), safe_box::Error> {
+    /// # use safe_box::{blocking::BlockingSafeBox, SafeBox};
+    /// let safe = BlockingSafeBox::open(SafeBox::builder().table("admins").connect("sqlite://secure.db"))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open(safe: impl Future<Output = Result<SafeBox, Error>>) -> Result<Self, Error> {
+        let rt = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start the tokio runtime");
+        let safe = rt.block_on(safe)?;
+        Ok(Self {
+            safe: Some(safe),
+            rt,
+        })
+    }
+
+    /// The wrapped [`SafeBox`].
+    pub fn inner(&self) -> &SafeBox {
+        self.safe.as_ref().expect("SafeBox is only taken on drop")
+    }
+
+    /// Run `f` on the internal runtime, for operations without a blocking equivalent:
+    /// ```no_run
+    /// # #[cfg(not(feature = "postgres"))]
+    /// # fn example() -> Result<(), safe_box::Error> {
+    /// # use safe_box::blocking::BlockingSafeBox;
+    /// let safe = BlockingSafeBox::new("secure.db")?;
+    /// let users = safe.block_on(safe.inner().export())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn block_on<F: Future>(&self, f: F) -> F::Output {
+        self.rt.block_on(f)
+    }
+
+    /// See [`SafeBox::create`].
+    pub fn create(&self, user: &str, pass: &str) -> Result<(), Error> {
+        self.block_on(self.inner().create(user, pass))
+    }
+
+    /// See [`SafeBox::verify`].
+    pub fn verify(&self, user: &str, pass: &str) -> Result<Option<Token>, Error> {
+        self.block_on(self.inner().verify(user, pass))
+    }
+
+    /// See [`SafeBox::verify_detailed`].
+    pub fn verify_detailed(&self, user: &str, pass: &str) -> Result<Option<LoginContext>, Error> {
+        self.block_on(self.inner().verify_detailed(user, pass))
+    }
+
+    /// See [`SafeBox::authenticate`].
+    pub fn authenticate(&self, user: &str, pass: &str) -> Result<VerifyOutcome, Error> {
+        self.block_on(self.inner().authenticate(user, pass))
+    }
+
+    /// See [`SafeBox::verify_token`].
+    pub fn verify_token(&self, token: impl AsRef<str>) -> Result<String, Error> {
+        self.inner().verify_token(token)
+    }
+
+    /// See [`SafeBox::verify_token_full`].
+    pub fn verify_token_full(&self, token: impl AsRef<str>) -> Result<(String, Duration), Error> {
+        self.inner().verify_token_full(token)
+    }
+
+    /// See [`SafeBox::invalidate_token`].
+    pub fn invalidate_token(&self, token: impl AsRef<str>) -> Result<(), Error> {
+        self.block_on(self.inner().invalidate_token(token))
+    }
+
+    /// See [`SafeBox::update`].
+    pub fn update(&self, user: &str, pass: &str, new: &str) -> Result<(), Error> {
+        self.block_on(self.inner().update(user, pass, new))
+    }
+
+    /// See [`SafeBox::change_password`].
+    pub fn change_password(&self, user: &str, old: &str, new: &str) -> Result<(), Error> {
+        self.block_on(self.inner().change_password(user, old, new))
+    }
+
+    /// See [`SafeBox::exists`].
+    pub fn exists(&self, user: &str) -> Result<bool, Error> {
+        self.block_on(self.inner().exists(user))
+    }
+
+    /// See [`SafeBox::last_login`].
+    pub fn last_login(&self, user: &str) -> Result<Option<SystemTime>, Error> {
+        self.block_on(self.inner().last_login(user))
+    }
+
+    /// See [`SafeBox::list_users`].
+    pub fn list_users(&self, offset: usize, limit: usize) -> Result<Vec<String>, Error> {
+        self.block_on(self.inner().list_users(offset, limit))
+    }
+
+    /// See [`SafeBox::user_cnt`].
+    pub fn user_cnt(&self) -> Result<usize, Error> {
+        self.block_on(self.inner().user_cnt())
+    }
+
+    /// See [`SafeBox::user_cnt_cached`].
+    pub fn user_cnt_cached(&self) -> Option<usize> {
+        self.inner().user_cnt_cached()
+    }
+
+    /// See [`SafeBox::rename`].
+    pub fn rename(&self, old: &str, new: &str) -> Result<(), Error> {
+        self.block_on(self.inner().rename(old, new))
+    }
+
+    /// See [`SafeBox::delete`].
+    pub fn delete(&self, user: &str, pass: &str) -> Result<(), Error> {
+        self.block_on(self.inner().delete(user, pass))
+    }
+
+    /// See [`SafeBox::delete_if_exists`].
+    pub fn delete_if_exists(&self, user: &str) -> Result<bool, Error> {
+        self.block_on(self.inner().delete_if_exists(user))
+    }
+
+    /// See [`SafeBox::ping`].
+    pub fn ping(&self) -> Result<(), Error> {
+        self.block_on(self.inner().ping())
+    }
+
+    /// See [`SafeBox::close`].
+    pub fn close(mut self) -> Result<(), Error> {
+        let safe = self.safe.take().expect("SafeBox is only taken on drop");
+        self.rt.block_on(safe.close())
+    }
+}
+
+impl Drop for BlockingSafeBox {
+    fn drop(&mut self) {
+        if let Some(safe) = self.safe.take() {
+            // connections may need the runtime to shut down
+            let _guard = self.rt.enter();
+            drop(safe);
+        }
+    }
+}
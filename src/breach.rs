@@ -0,0 +1,56 @@
+//! Offline check of passwords against a list of breached ones, with the `breach-check` feature.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+/// A list of the SHA-1 digests of breached passwords,
+/// set with [`SafeBox::with_breach_list`](crate::SafeBox::with_breach_list).
+///
+/// The list is a directory laid out like the range API of Have I Been Pwned,
+/// as made by its downloader:
+/// the digests starting with the 5 hexadecimal digits `XXXXX` are in the file `XXXXX.txt`,
+/// which holds the remaining 35 digits of each, one per line and sorted,
+/// optionally followed by `:` and a count.
+/// Checking a password thus reads a single small file, and nothing goes over the network.
+#[derive(Debug, Clone)]
+pub struct BreachList {
+    dir: PathBuf,
+}
+
+impl BreachList {
+    /// Use the list in the directory `dir`.
+    /// Fail if `dir` is not a directory.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            let msg = format!("'{}' is not a directory", dir.display());
+            return Err(io::Error::new(ErrorKind::NotFound, msg));
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// Whether the digest of `pass` is in the list.
+    /// Fail if the file of its prefix is missing, as the list is then incomplete.
+    pub fn contains(&self, pass: &[u8]) -> io::Result<bool> {
+        let digest = Sha1::digest(pass)
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<String>();
+        let (prefix, suffix) = digest.split_at(5);
+        let range = fs::read_to_string(self.dir.join(format!("{prefix}.txt")))?;
+        let found = range
+            .lines()
+            .map(|l| l.split(':').next().unwrap_or_default().trim())
+            .collect::<Vec<_>>()
+            .binary_search_by(|l| l.to_ascii_uppercase().as_str().cmp(suffix))
+            .is_ok();
+        Ok(found)
+    }
+}
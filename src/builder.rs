@@ -0,0 +1,644 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicUsize, Arc, Mutex, RwLock},
+    time::Duration,
+};
+#[cfg(not(feature = "postgres"))]
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use argon2::{Algorithm, ParamsBuilder};
+use rand_core::RngCore;
+#[cfg(all(feature = "sqlcipher", not(feature = "postgres")))]
+use sqlx::query_scalar;
+#[cfg(not(feature = "postgres"))]
+use sqlx::sqlite::SqliteJournalMode;
+use sqlx::{query, Row};
+
+#[cfg(feature = "breach-check")]
+use crate::BreachList;
+#[cfg(not(feature = "postgres"))]
+use crate::BUSY_TIMEOUT;
+use crate::{
+    db::{self, ConnectOptions, Pool, PoolOptions},
+    from_unix_ms,
+    hasher::{check_params, Argon2Hasher, Hasher, Readers, Rng},
+    key_from_str,
+    reset::RESET_TTL,
+    schema, sign, split_scopes,
+    token::{check_token_len, random_token, TOKEN_LEN},
+    unix_ms, Clock, Error, Lockout, MemoryTokenStore, Observer, PasswordHasher, PasswordPolicy,
+    RandomTokenGen, SafeBox, SystemClock, Tables, TokenEntry, TokenGen, TokenMode, TokenStore,
+    MAX_CONNECTIONS, MAX_USERNAME_LEN, TOKEN_COLUMNS, TOKEN_TTL,
+};
+
+/// Builder of a [`SafeBox`], created by [`SafeBox::builder`].
+/// Unset options keep the defaults of [`SafeBox::connect`].
+pub struct SafeBoxBuilder {
+    max_connections: u32,
+    table: Option<String>,
+    param: ParamsBuilder,
+    algorithm: Option<Algorithm>,
+    salt_len: Option<usize>,
+    rng: Option<Rng>,
+    hasher: Option<Box<dyn PasswordHasher>>,
+    readers: Readers,
+    pepper: Option<Vec<u8>>,
+    token_mode: TokenMode,
+    token_gen: Option<Box<dyn TokenGen>>,
+    token_len: usize,
+    token_store: Option<Box<dyn TokenStore>>,
+    clock: Option<Box<dyn Clock>>,
+    observer: Option<Box<dyn Observer>>,
+    token_ttl: Duration,
+    reset_ttl: Duration,
+    max_user_token: Option<usize>,
+    case_insensitive: bool,
+    max_username_len: usize,
+    hide_missing_users: bool,
+    lockout: Option<Lockout>,
+    policy: Option<PasswordPolicy>,
+    #[cfg(feature = "breach-check")]
+    breach: Option<BreachList>,
+    history: usize,
+    migration_target: Option<String>,
+    read_only: bool,
+    cache_user_cnt: bool,
+    #[cfg(not(feature = "postgres"))]
+    wal: bool,
+    #[cfg(not(feature = "postgres"))]
+    busy_timeout: Duration,
+    #[cfg(all(feature = "sqlcipher", not(feature = "postgres")))]
+    key: Option<String>,
+}
+
+impl Default for SafeBoxBuilder {
+    fn default() -> Self {
+        Self {
+            max_connections: MAX_CONNECTIONS,
+            table: None,
+            param: ParamsBuilder::default(),
+            algorithm: None,
+            salt_len: None,
+            rng: None,
+            hasher: None,
+            readers: Readers::new(),
+            pepper: None,
+            token_mode: TokenMode::Memory,
+            token_gen: None,
+            token_len: TOKEN_LEN,
+            token_store: None,
+            clock: None,
+            observer: None,
+            token_ttl: TOKEN_TTL,
+            reset_ttl: RESET_TTL,
+            max_user_token: None,
+            case_insensitive: false,
+            max_username_len: MAX_USERNAME_LEN,
+            hide_missing_users: false,
+            lockout: None,
+            policy: None,
+            #[cfg(feature = "breach-check")]
+            breach: None,
+            history: 0,
+            migration_target: None,
+            read_only: false,
+            cache_user_cnt: false,
+            #[cfg(not(feature = "postgres"))]
+            wal: true,
+            #[cfg(not(feature = "postgres"))]
+            busy_timeout: BUSY_TIMEOUT,
+            #[cfg(all(feature = "sqlcipher", not(feature = "postgres")))]
+            key: None,
+        }
+    }
+}
+
+impl SafeBoxBuilder {
+    /// Set the maximum number of connections to the database.
+    /// Defaults to 10.
+    pub fn max_connections(mut self, max: u32) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Store users in `table` instead of `main`,
+    /// so that several independent stores can share a database.
+    /// Opening the database fails with [`Error::InvalidConfig`] unless `table` is made of
+    /// ASCII letters, digits and underscores, and does not start with a digit.
+    /// It is lowercased, and may be an SQL keyword such as `order` or `user`.
+    ///
+    /// Previous passwords, tokens and attributes are stored in `<table>_password_history`,
+    /// `<table>_token` and `<table>_meta`, which must not be used by anything else.
+    /// Names of tables of the default store, such as `token`, and names ending like those,
+    /// such as `admins_token`, are refused, as the stores would share tables.
+    pub fn table(mut self, table: &str) -> Self {
+        self.table = Some(table.to_owned());
+        self
+    }
+
+    /// Set the Argon2 memory cost in KiB.
+    pub fn memory_cost(mut self, m_cost: u32) -> Self {
+        self.param.m_cost(m_cost);
+        self
+    }
+
+    /// Set the Argon2 number of iterations.
+    pub fn time_cost(mut self, t_cost: u32) -> Self {
+        self.param.t_cost(t_cost);
+        self
+    }
+
+    /// Set the Argon2 degree of parallelism.
+    pub fn parallelism(mut self, p_cost: u32) -> Self {
+        self.param.p_cost(p_cost);
+        self
+    }
+
+    /// Set the length of the hash in bytes, which must be from 10 to 64.
+    pub fn hash_length(mut self, len: usize) -> Self {
+        self.param.output_len(len);
+        self
+    }
+
+    /// Hash with the Argon2 variant `algorithm`, see [`Argon2Hasher::with_algorithm`].
+    pub fn argon2_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = Some(algorithm);
+        self
+    }
+
+    /// Set the length of new salts in bytes, see [`Argon2Hasher::with_salt_length`].
+    pub fn salt_length(mut self, len: usize) -> Self {
+        self.salt_len = Some(len);
+        self
+    }
+
+    /// Make salts and tokens with `rng` instead of the random number generator of the OS,
+    /// so that tests can produce the same hashes and tokens every time.
+    /// Never use a predictable `rng` in production.
+    ///
+    /// Hashers set with [`SafeBoxBuilder::hasher`] and generators set with
+    /// [`SafeBoxBuilder::token_gen`] keep their own randomness.
+    pub fn rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.rng = Some(Rng::new(rng));
+        self
+    }
+
+    /// Hash passwords with `hasher` instead of Argon2.
+    /// The Argon2 parameters and salt length set on this builder are then ignored.
+    /// Stored hashes are also verified with `hasher`, so it must understand them,
+    /// unless their algorithm is registered with [`SafeBoxBuilder::read_hasher`].
+    pub fn hasher(mut self, hasher: impl PasswordHasher + 'static) -> Self {
+        self.hasher = Some(Box::new(hasher));
+        self
+    }
+
+    /// Verify stored hashes of the algorithm `algorithm`, as named in PHC strings,
+    /// with `hasher`, while new hashes are still made by [`SafeBoxBuilder::hasher`] or Argon2.
+    /// Several algorithms can be registered, for example during a migration,
+    /// and hashes of the others are verified by the hasher of new ones.
+    ///
+    /// Hashes of a registered algorithm are replaced as their users log in
+    /// if the hasher of new ones finds them [outdated](PasswordHasher::outdated),
+    /// as [`Argon2Hasher`] does with those of any other algorithm.
+    /// ```
+    /// # #[cfg(feature = "scrypt")]
+    /// # fn example() {
+    /// use safe_box::{Argon2Hasher, SafeBoxBuilder, ScryptHasher};
+    ///
+    /// let builder = SafeBoxBuilder::default()
+    ///     .hasher(Argon2Hasher::default())
+    ///     .read_hasher("scrypt", ScryptHasher::default());
+    /// # }
+    /// ```
+    pub fn read_hasher(mut self, algorithm: &str, hasher: impl PasswordHasher + 'static) -> Self {
+        self.readers.insert(algorithm.to_owned(), Box::new(hasher));
+        self
+    }
+
+    /// Mix the secret `pepper` into every password before hashing it.
+    /// The pepper should be kept out of the database,
+    /// so that leaking the database alone is not enough to crack the hashes.
+    ///
+    /// Changing or removing the pepper invalidates all existing hashes,
+    /// and setting it on a database with existing users locks them out.
+    pub fn pepper(mut self, pepper: &[u8]) -> Self {
+        self.pepper = Some(pepper.to_owned());
+        self
+    }
+
+    /// Also store tokens in the database, so that they survive restarts.
+    /// Tokens that have not expired yet are loaded back into memory.
+    ///
+    /// A token is written to the database when it is issued, extended or invalidated,
+    /// before the call returns, so even an abrupt exit loses none of them
+    /// and there is nothing to flush on shutdown.
+    pub fn persistent_tokens(mut self) -> Self {
+        self.token_mode = TokenMode::Persistent;
+        self
+    }
+
+    /// Sign tokens with `secret` instead of storing them.
+    /// Any `SafeBox` sharing the same `secret` accepts the tokens until they expire,
+    /// which allows several instances to authenticate the same clients.
+    ///
+    /// Signed tokens cannot be invalidated before they expire,
+    /// and [`SafeBox::active_tokens`] cannot list them.
+    pub fn signed_tokens(mut self, secret: &[u8]) -> Self {
+        self.token_mode = TokenMode::Signed(sign::key(secret));
+        self
+    }
+
+    /// Generate tokens with `gen` instead of [`RandomTokenGen`].
+    /// Ignored with [`SafeBoxBuilder::signed_tokens`].
+    pub fn token_gen(mut self, gen: impl TokenGen + 'static) -> Self {
+        self.token_gen = Some(Box::new(gen));
+        self
+    }
+
+    /// Make random tokens of `len` bytes instead of 32, for example to fit tokens in small devices.
+    /// The default is kept at 32 bytes, which is what tokens always had, for compatibility.
+    /// Opening the database fails with [`Error::InvalidConfig`] if `len` is less than 16 bytes,
+    /// which would make tokens guessable.
+    /// Ignored with [`SafeBoxBuilder::token_gen`] or [`SafeBoxBuilder::signed_tokens`].
+    pub fn token_length(mut self, len: usize) -> Self {
+        self.token_len = len;
+        self
+    }
+
+    /// Tell the time with `clock` instead of [`SystemClock`],
+    /// for example to test the expiry of tokens without waiting.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Keep tokens in `store` instead of a [`MemoryTokenStore`],
+    /// for example to share them with other processes.
+    /// With [`SafeBoxBuilder::persistent_tokens`], the stored tokens are also loaded into `store`.
+    /// Ignored with [`SafeBoxBuilder::signed_tokens`].
+    pub fn token_store(mut self, store: impl TokenStore + 'static) -> Self {
+        self.token_store = Some(Box::new(store));
+        self
+    }
+
+    /// See [`SafeBox::with_token_ttl`].
+    pub fn token_ttl(mut self, ttl: Duration) -> Self {
+        self.token_ttl = ttl;
+        self
+    }
+
+    /// Set the lifetime of tokens issued by [`SafeBox::issue_reset_token`].
+    /// Defaults to 15 minutes.
+    pub fn reset_token_ttl(mut self, ttl: Duration) -> Self {
+        self.reset_ttl = ttl;
+        self
+    }
+
+    /// See [`SafeBox::with_max_tokens_per_user`].
+    pub fn max_tokens_per_user(mut self, max: usize) -> Self {
+        self.max_user_token = Some(max.max(1));
+        self
+    }
+
+    /// See [`SafeBox::with_case_insensitive`].
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// See [`SafeBox::with_max_username_len`].
+    pub fn max_username_len(mut self, max: usize) -> Self {
+        self.max_username_len = max;
+        self
+    }
+
+    /// Make [`SafeBox::verify`], [`SafeBox::authenticate`] and [`SafeBox::verify_many`]
+    /// treat missing users like wrong passwords,
+    /// so that login attempts do not reveal which users exist.
+    /// A password is then checked against a dummy hash for missing users,
+    /// which takes as long as checking a real one.
+    ///
+    /// Other operations still fail with [`Error::UserNotExist`] for missing users.
+    /// The dummy hash is made when opening the database.
+    pub fn hide_missing_users(mut self) -> Self {
+        self.hide_missing_users = true;
+        self
+    }
+
+    /// See [`SafeBox::with_lockout`].
+    pub fn lockout(mut self, max_failures: u32, window: Duration, cooldown: Duration) -> Self {
+        self.lockout = Some(Lockout {
+            max_failures,
+            window,
+            cooldown,
+        });
+        self
+    }
+
+    /// See [`SafeBox::with_password_policy`].
+    pub fn password_policy(
+        mut self,
+        policy: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// See [`SafeBox::with_breach_list`].
+    #[cfg(feature = "breach-check")]
+    pub fn breach_list(mut self, list: BreachList) -> Self {
+        self.breach = Some(list);
+        self
+    }
+
+    /// Notify `observer` of account events, see [`Observer`].
+    /// By default there is no observer.
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// See [`SafeBox::with_password_history`].
+    pub fn password_history(mut self, n: usize) -> Self {
+        self.history = n;
+        self
+    }
+
+    /// See [`SafeBox::with_migration_target`].
+    pub fn migration_target(mut self, algorithm: &str) -> Self {
+        self.migration_target = Some(algorithm.to_owned());
+        self
+    }
+
+    /// Open the database read-only, for example for audit tools.
+    /// Methods that would write to it then fail with [`Error::ReadOnly`] instead.
+    /// Passwords can still be checked, but neither is the login time recorded
+    /// nor are outdated hashes replaced,
+    /// and tokens are kept in memory only, even with [`SafeBoxBuilder::persistent_tokens`].
+    ///
+    /// The tables are not created, so the database must have been opened for writing before.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Keep a count of users, read by [`SafeBox::user_cnt_cached`] without querying the database.
+    /// The users are counted once when the database is opened.
+    pub fn cache_user_cnt(mut self) -> Self {
+        self.cache_user_cnt = true;
+        self
+    }
+
+    /// Use the write-ahead log of SQLite, which is enabled by default.
+    /// Readers then no longer block writers and the other way around,
+    /// so concurrent operations rarely fail with "database is locked".
+    ///
+    /// The database then comes with `-wal` and `-shm` files next to it,
+    /// which must be kept along with it, and cannot live on a network file system.
+    /// Once set, the mode is remembered by the database file.
+    /// Disabling it keeps the journal mode of the database as is.
+    /// Has no effect on databases in memory.
+    #[cfg(not(feature = "postgres"))]
+    pub fn wal(mut self, wal: bool) -> Self {
+        self.wal = wal;
+        self
+    }
+
+    /// Set how long an SQLite connection waits for another to release a lock
+    /// before failing with "database is locked".
+    /// Defaults to 5 seconds.
+    #[cfg(not(feature = "postgres"))]
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Encrypt the whole database file with SQLCipher, using the passphrase `key`,
+    /// which is set before any other query on each connection.
+    /// Passwords are hashed the same way; this only protects the file at rest.
+    ///
+    /// This needs SQLite to be built with SQLCipher,
+    /// for example by enabling the `bundled-sqlcipher` feature of `libsqlite3-sys`
+    /// in the application, at the version used by `sqlx`.
+    /// With a plain SQLite, which would ignore the key and leave the file unencrypted,
+    /// opening the database fails with [`Error::InvalidConfig`] before anything is written.
+    /// Opening an existing database with the wrong key fails with [`Error::Corrupt`],
+    /// as SQLite reports "file is not a database".
+    /// ```no_run
+    /// # async fn example() -> Result<(), safe_box::Error> {
+    /// let safe = safe_box::SafeBoxBuilder::default()
+    ///     .database_key("correct horse battery staple")
+    ///     .build("secure.db")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(all(feature = "sqlcipher", not(feature = "postgres")))]
+    pub fn database_key(mut self, key: &str) -> Self {
+        self.key = Some(key.to_owned());
+        self
+    }
+
+    /// Open the SQLite database file at `p`, creating it if missing.
+    #[cfg(not(feature = "postgres"))]
+    pub async fn build(self, p: impl AsRef<Path>) -> Result<SafeBox, Error> {
+        let opt = ConnectOptions::default()
+            .filename(p)
+            .create_if_missing(true);
+        let pool = PoolOptions::new().max_connections(self.max_connections);
+        self.open(opt, pool).await
+    }
+
+    /// Like [`SafeBoxBuilder::build`], but a corrupt database file is first moved aside
+    /// to `{p}.corrupt-{t}`, where `t` is the current time in unix milliseconds,
+    /// and replaced by a fresh one, like [`SafeBox::open_or_recreate`].
+    ///
+    /// The integrity check reads the whole database, which can take a while if it is large.
+    /// With `database_key` and the `sqlcipher` feature, it reads the database with the key,
+    /// and a database the key does not open is not replaced but fails with [`Error::Corrupt`],
+    /// as the key may be wrong.
+    #[cfg(not(feature = "postgres"))]
+    pub async fn build_or_recreate(self, p: impl AsRef<Path>) -> Result<SafeBox, Error> {
+        let p = p.as_ref();
+        #[cfg(feature = "sqlcipher")]
+        let key = self.key.as_deref();
+        #[cfg(not(feature = "sqlcipher"))]
+        let key = None;
+        if db::corrupt_file(p, key).await? {
+            let t = unix_ms(SystemTime::now());
+            for ext in ["", "-wal", "-shm", "-journal"] {
+                let mut file = p.as_os_str().to_owned();
+                file.push(ext);
+                let file = PathBuf::from(file);
+                if file.exists() {
+                    let mut backup = file.clone().into_os_string();
+                    backup.push(format!(".corrupt-{t}"));
+                    fs::rename(&file, backup)?;
+                }
+            }
+        }
+        self.build(p).await
+    }
+
+    /// Create an SQLite database in memory like [`SafeBox::new_in_memory`].
+    /// The maximum number of connections is ignored.
+    #[cfg(not(feature = "postgres"))]
+    pub async fn build_in_memory(self) -> Result<SafeBox, Error> {
+        let opt = ConnectOptions::default().in_memory(true);
+        let pool = PoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+        self.open(opt, pool).await
+    }
+
+    /// Connect to the database at `url` like [`SafeBox::connect`].
+    pub async fn connect(self, url: &str) -> Result<SafeBox, Error> {
+        let pool = PoolOptions::new().max_connections(self.max_connections);
+        self.open(url.parse()?, pool).await
+    }
+
+    async fn open(self, opt: ConnectOptions, pool: PoolOptions) -> Result<SafeBox, Error> {
+        #[cfg(not(feature = "postgres"))]
+        let opt = if self.wal && !self.read_only {
+            opt.journal_mode(SqliteJournalMode::Wal)
+        } else {
+            opt
+        }
+        .busy_timeout(self.busy_timeout)
+        .read_only(self.read_only);
+        #[cfg(all(feature = "sqlcipher", not(feature = "postgres")))]
+        let opt = match &self.key {
+            Some(key) => opt.pragma("key", db::key_pragma(key)),
+            None => opt,
+        };
+        #[cfg(feature = "postgres")]
+        let opt = if self.read_only {
+            opt.options([("default_transaction_read_only", "on")])
+        } else {
+            opt
+        };
+        let pool = pool.connect_with(opt).await?;
+        #[cfg(all(feature = "sqlcipher", not(feature = "postgres")))]
+        if self.key.is_some() {
+            // only SQLCipher knows this pragma, plain SQLite returns no row
+            let q = query_scalar::<_, String>("PRAGMA cipher_version");
+            if q.fetch_optional(&pool).await?.is_none() {
+                pool.close().await;
+                return Err(Error::InvalidConfig(
+                    "database_key needs SQLite to be built with SQLCipher".to_owned(),
+                ));
+            }
+        }
+        self.build_from_pool(pool).await
+    }
+
+    /// Use `pool`, through which the application may also reach its own tables,
+    /// rather than opening another connection to the same database, like [`SafeBox::from_pool`].
+    /// The tables are created on it, unless [`SafeBoxBuilder::read_only`] is set.
+    ///
+    /// The options for connecting, such as [`SafeBoxBuilder::max_connections`], are ignored,
+    /// as the pool is already configured.
+    /// A single connection can be shared through a pool with a maximum of one connection.
+    pub async fn build_from_pool(self, pool: Pool) -> Result<SafeBox, Error> {
+        check_token_len(self.token_len)?;
+        let hasher = match self.hasher {
+            Some(hasher) => hasher,
+            None => {
+                let param = self
+                    .param
+                    .build()
+                    .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+                check_params(&param)?;
+                let mut hasher = Argon2Hasher::new(param);
+                if let Some(algorithm) = self.algorithm {
+                    hasher = hasher.with_algorithm(algorithm);
+                }
+                if let Some(rng) = &self.rng {
+                    hasher = hasher.with_shared_rng(rng.clone());
+                }
+                match self.salt_len {
+                    Some(len) => Box::new(hasher.with_salt_length(len)?),
+                    None => Box::new(hasher),
+                }
+            }
+        };
+        let hasher = Hasher::new(hasher, self.readers, self.pepper.as_deref());
+        let dummy = if self.hide_missing_users {
+            Some(hasher.hash(RandomTokenGen.generate().as_bytes())?)
+        } else {
+            None
+        };
+        let tables = match &self.table {
+            Some(table) => Tables::new(table)?,
+            None => Tables::default(),
+        };
+        if !self.read_only {
+            schema::upgrade(&pool, &tables).await?;
+        }
+        let user_cnt = if self.cache_user_cnt {
+            Some(AtomicUsize::new(db::count(&pool, &tables.main).await?))
+        } else {
+            None
+        };
+        let clock = self.clock.unwrap_or_else(|| Box::new(SystemClock));
+        let token = self
+            .token_store
+            .unwrap_or_else(|| Box::new(MemoryTokenStore::new()));
+        if matches!(self.token_mode, TokenMode::Persistent) && !self.read_only {
+            query(&tables.init_token()).execute(&pool).await?;
+            db::migrate(&pool, &tables.token, TOKEN_COLUMNS).await?;
+            let q = format!(
+                r#"SELECT token, id, "user", issued_at, expire_at, scopes FROM "{}" WHERE expire_at > $1"#,
+                tables.token
+            );
+            let q = query(&q).bind(unix_ms(clock.now()));
+            for row in q.fetch_all(&pool).await? {
+                let entry = TokenEntry {
+                    id: row.try_get("id")?,
+                    user: row.try_get("user")?,
+                    issued: from_unix_ms(row.try_get("issued_at")?),
+                    expire: from_unix_ms(row.try_get("expire_at")?),
+                    scopes: split_scopes(row.try_get("scopes")?),
+                };
+                token.insert(key_from_str(row.try_get("token")?)?, entry);
+            }
+        }
+        Ok(SafeBox {
+            pool,
+            tables,
+            hasher: RwLock::new(Arc::new(hasher)),
+            token,
+            token_ttl: self.token_ttl,
+            token_mode: self.token_mode,
+            clock,
+            observer: self.observer,
+            token_gen: match (self.token_gen, self.rng, self.token_len) {
+                (Some(gen), _, _) => gen,
+                (None, None, TOKEN_LEN) => Box::new(RandomTokenGen),
+                (None, rng, len) => Box::new(move || random_token(rng.as_ref(), len)),
+            },
+            max_user_token: self.max_user_token,
+            case_insensitive: self.case_insensitive,
+            max_username_len: self.max_username_len,
+            lockout: self.lockout,
+            failure: Mutex::new(HashMap::new()),
+            #[cfg(feature = "totp")]
+            totp_failure: Mutex::new(HashMap::new()),
+            policy: self.policy,
+            #[cfg(feature = "breach-check")]
+            breach: self.breach,
+            history: self.history,
+            migration_target: self.migration_target,
+            dummy: RwLock::new(dummy),
+            reset: Mutex::new(HashMap::new()),
+            reset_ttl: self.reset_ttl,
+            read_only: self.read_only,
+            user_cnt,
+        })
+    }
+}
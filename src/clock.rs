@@ -0,0 +1,45 @@
+//! Source of the current time of a [`SafeBox`](crate::SafeBox).
+
+use std::time::SystemTime;
+
+/// Source of the current time, used for the expiry of tokens and the lockout policy,
+/// set with [`SafeBoxBuilder::clock`](crate::SafeBoxBuilder::clock).
+///
+/// Closures returning a `SystemTime` are clocks too,
+/// so that tests can move time forward instead of sleeping:
+/// ```
+/// use std::{
+///     sync::{Arc, Mutex},
+///     time::{Duration, SystemTime},
+/// };
+///
+/// use safe_box::Clock;
+///
+/// let now = Arc::new(Mutex::new(SystemTime::UNIX_EPOCH));
+/// let clock = {
+///     let now = now.clone();
+///     move || *now.lock().unwrap()
+/// };
+/// *now.lock().unwrap() += Duration::from_secs(60);
+/// assert_eq!(clock.now(), SystemTime::UNIX_EPOCH + Duration::from_secs(60));
+/// ```
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+}
+
+impl<F: Fn() -> SystemTime + Send + Sync> Clock for F {
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
+
+/// The default clock, telling the time of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,66 @@
+//! Declarative configuration of a [`SafeBox`](crate::SafeBox).
+//!
+//! With the `serde` feature, the configuration can be deserialized,
+//! for example from a TOML file:
+//! ```toml
+//! database = "secure.db"
+//! token_ttl_secs = 600
+//!
+//! [argon2]
+//! algorithm = "argon2id"
+//! memory_cost = 65536
+//! time_cost = 3
+//!
+//! [lockout]
+//! max_failures = 5
+//! window_secs = 300
+//! cooldown_secs = 900
+//! ```
+
+/// Configuration of a [`SafeBox`](crate::SafeBox), see [`SafeBox::from_config`](crate::SafeBox::from_config).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct SafeConfig {
+    /// Path of the SQLite database file,
+    /// or URL of the database with the `postgres` feature.
+    pub database: String,
+    /// Argon2 parameters, defaulting to those of [`Argon2Hasher`](crate::Argon2Hasher).
+    pub argon2: Option<Argon2Config>,
+    /// Lifetime of tokens in seconds, defaulting to 5 minutes.
+    pub token_ttl_secs: Option<u64>,
+    /// Hash algorithm to migrate passwords to, see [`SafeBox::with_migration_target`](crate::SafeBox::with_migration_target).
+    pub migration_target: Option<String>,
+    /// Lockout policy, disabled by default.
+    pub lockout: Option<LockoutConfig>,
+}
+
+/// Argon2 parameters of a [`SafeConfig`].
+/// Unset parameters keep their defaults.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct Argon2Config {
+    /// Variant, which is `argon2id`, `argon2i` or `argon2d`.
+    pub algorithm: Option<String>,
+    /// Memory cost in KiB.
+    pub memory_cost: Option<u32>,
+    /// Number of iterations.
+    pub time_cost: Option<u32>,
+    /// Degree of parallelism.
+    pub parallelism: Option<u32>,
+    /// Length of the hash in bytes.
+    pub hash_length: Option<usize>,
+    /// Length of new salts in bytes.
+    pub salt_length: Option<usize>,
+}
+
+/// Lockout policy of a [`SafeConfig`], see [`SafeBox::with_lockout`](crate::SafeBox::with_lockout).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct LockoutConfig {
+    /// Number of failed password checks that locks a user out.
+    pub max_failures: u32,
+    /// Period in seconds within which failures are counted.
+    pub window_secs: u64,
+    /// Duration in seconds of the lockout.
+    pub cooldown_secs: u64,
+}
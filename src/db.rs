@@ -0,0 +1,144 @@
+//! Database backend, selected at compile time.
+//!
+//! SQLite is used by default, and Postgres with the `postgres` feature,
+//! which takes precedence if both are enabled.
+//! Queries use numbered `$N` placeholders, which both backends accept,
+//! and quote the `user` column, which is a reserved word in Postgres,
+//! as well as table names, which may be reserved words too.
+
+#[cfg(not(feature = "postgres"))]
+use std::path::Path;
+
+use sqlx::{query, query_scalar};
+#[cfg(not(feature = "postgres"))]
+use sqlx::{ConnectOptions as _, Connection as _, Row};
+
+use crate::Error;
+
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("either the `sqlite` or the `postgres` feature must be enabled");
+
+#[cfg(feature = "postgres")]
+pub(crate) type Db = sqlx::Postgres;
+#[cfg(not(feature = "postgres"))]
+pub(crate) type Db = sqlx::Sqlite;
+
+pub(crate) type Pool = sqlx::Pool<Db>;
+
+/// A single connection to the database.
+pub(crate) type Connection = <Db as sqlx::Database>::Connection;
+
+/// Options to connect to the database.
+pub(crate) type ConnectOptions = <Connection as sqlx::Connection>::Options;
+
+/// Options of the connection pool.
+pub(crate) type PoolOptions = sqlx::pool::PoolOptions<Db>;
+
+/// Primary result code of the SQLite error `e`, which extended result codes keep in their low byte.
+#[cfg(not(feature = "postgres"))]
+fn result_code(e: &sqlx::Error) -> Option<i32> {
+    let sqlx::Error::Database(e) = e else {
+        return None;
+    };
+    e.code()
+        .and_then(|c| c.parse::<i32>().ok())
+        .map(|c| c & 0xff)
+}
+
+/// Whether `e` means that the database is corrupt.
+#[cfg(not(feature = "postgres"))]
+pub(crate) fn is_corrupt(e: &sqlx::Error) -> bool {
+    // SQLITE_CORRUPT and SQLITE_NOTADB
+    matches!(result_code(e), Some(11 | 26))
+}
+
+/// Whether `e` means that the database is corrupt.
+#[cfg(feature = "postgres")]
+pub(crate) fn is_corrupt(e: &sqlx::Error) -> bool {
+    // data_corrupted and index_corrupted.
+    let sqlx::Error::Database(e) = e else {
+        return false;
+    };
+    e.code().is_some_and(|c| c == "XX001" || c == "XX002")
+}
+
+/// Value of the SQLCipher `key` pragma setting the passphrase `key`.
+#[cfg(not(feature = "postgres"))]
+pub(crate) fn key_pragma(key: &str) -> String {
+    format!("'{}'", key.replace('\'', "''"))
+}
+
+/// Whether the SQLite database file at `p` exists and fails a quick integrity check,
+/// reading it with the SQLCipher passphrase `key` if any.
+///
+/// With a key, a file that is not a database is what a wrong key looks like,
+/// so it fails with [`Error::Corrupt`] instead of counting as corrupt.
+#[cfg(not(feature = "postgres"))]
+pub(crate) async fn corrupt_file(p: &Path, key: Option<&str>) -> Result<bool, Error> {
+    if !p.exists() {
+        return Ok(false);
+    }
+    let check = async {
+        let opt = ConnectOptions::default().filename(p);
+        let opt = match key {
+            Some(key) => opt.pragma("key", key_pragma(key)),
+            None => opt,
+        };
+        let mut conn = opt.connect().await?;
+        let res: String = query_scalar("PRAGMA quick_check")
+            .fetch_one(&mut conn)
+            .await?;
+        conn.close().await?;
+        Ok(res)
+    };
+    match check.await {
+        Ok(res) => Ok(res != "ok"),
+        // SQLITE_NOTADB
+        Err(e) if key.is_some() && result_code(&e) == Some(26) => Err(e.into()),
+        Err(e) if is_corrupt(&e) => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Count the rows of `table`.
+pub(crate) async fn count(pool: &Pool, table: &str) -> Result<usize, Error> {
+    let q = format!(r#"SELECT COUNT(*) FROM "{table}""#);
+    let n: i64 = query_scalar(&q).fetch_one(pool).await?;
+    Ok(n as usize)
+}
+
+/// Add the `columns`, with their types, missing from an existing `table`.
+#[cfg(not(feature = "postgres"))]
+pub(crate) async fn migrate(
+    pool: &Pool,
+    table: &str,
+    columns: &[(&str, &str)],
+) -> Result<(), Error> {
+    let q = query("SELECT name FROM pragma_table_info($1)").bind(table);
+    let v = q.fetch_all(pool).await?;
+    let cols = v
+        .iter()
+        .map(|row| row.try_get("name"))
+        .collect::<Result<Vec<&str>, _>>()?;
+    for (col, ty) in columns {
+        if !cols.contains(col) {
+            let q = format!(r#"ALTER TABLE "{table}" ADD COLUMN {col} {ty}"#);
+            query(&q).execute(pool).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Add the `columns`, with their types, missing from an existing `table`.
+#[cfg(feature = "postgres")]
+pub(crate) async fn migrate(
+    pool: &Pool,
+    table: &str,
+    columns: &[(&str, &str)],
+) -> Result<(), Error> {
+    for (col, ty) in columns {
+        let q = format!(r#"ALTER TABLE "{table}" ADD COLUMN IF NOT EXISTS {col} {ty}"#);
+        query(&q).execute(pool).await?;
+    }
+    Ok(())
+}
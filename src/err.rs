@@ -1,10 +1,9 @@
-use thiserror::Error;
-
-#[derive(Error, Debug)]
-pub enum SafeBoxError {
+/// Error returned by the operations of a [`SafeBox`](crate::SafeBox).
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
     /// Internal SQL error.
     #[error(transparent)]
-    SQL(#[from] sqlx::error::Error),
+    SQL(sqlx::error::Error),
     /// Cryptography error with hashed passwords.
     #[error("{0}")]
     Crypto(crypto::password_hash::Error),
@@ -15,21 +14,145 @@ pub enum SafeBoxError {
     #[error("user '{0}' already exists")]
     UserAlreadyExist(String),
 
-    #[error("invalid password '{pass}' for user 'username'")]
-    BadPass {
-        user: String,
-        pass: String,
-    },
+    /// The password is not kept, so that it cannot end up in logs.
+    #[error("invalid password for user '{user}'")]
+    BadPass { user: String },
+
+    #[error("wrong password for user '{0}'")]
+    WrongPassword(String),
+
+    #[error("invalid username: {0}")]
+    InvalidUsername(String),
+
+    #[error("weak password: {0}")]
+    WeakPassword(String),
+
+    /// The password is in the `BreachList` set up with the `breach-check` feature.
+    #[error("password appears in a list of breached passwords")]
+    BreachedPassword,
+
+    #[error("password of user '{0}' was used recently")]
+    PasswordReused(String),
+
+    #[error("user '{0}' is locked after too many failed attempts")]
+    AccountLocked(String),
+
+    #[error("user '{0}' is disabled")]
+    AccountDisabled(String),
 
     #[error("invalid token '{0}'")]
     BadToken(String),
 
     #[error("invalid database: {0}")]
     InvalidData(String),
+
+    /// The database is corrupt, for example after a truncated write.
+    #[error("corrupt database: {0}")]
+    Corrupt(#[source] sqlx::error::Error),
+
+    /// The [`SafeBox`](crate::SafeBox) was opened read-only, see [`SafeBoxBuilder::read_only`](crate::SafeBoxBuilder::read_only).
+    #[error("the database was opened read-only")]
+    ReadOnly,
+
+    /// I/O error on the database file.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
 }
 
-impl From<crypto::password_hash::Error> for SafeBoxError {
+/// Former name of [`Error`].
+#[deprecated(note = "use `Error` instead")]
+pub type SafeBoxError = Error;
+
+impl From<sqlx::error::Error> for Error {
+    fn from(value: sqlx::error::Error) -> Self {
+        if crate::db::is_corrupt(&value) {
+            Error::Corrupt(value)
+        } else {
+            Error::SQL(value)
+        }
+    }
+}
+
+impl From<crypto::password_hash::Error> for Error {
     fn from(value: crypto::password_hash::Error) -> Self {
-        SafeBoxError::Crypto(value)
+        Error::Crypto(value)
+    }
+}
+
+impl Error {
+    /// Stable identifier of the kind of error, such as `user_not_exist`,
+    /// for clients to match on rather than on messages.
+    /// ```
+    /// assert_eq!(safe_box::Error::ReadOnly.code(), "read_only");
+    /// ```
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::SQL(_) => "sql",
+            Error::Crypto(_) => "crypto",
+            Error::UserNotExist(_) => "user_not_exist",
+            Error::UserAlreadyExist(_) => "user_already_exist",
+            Error::BadPass { .. } => "bad_pass",
+            Error::WrongPassword(_) => "wrong_password",
+            Error::InvalidUsername(_) => "invalid_username",
+            Error::WeakPassword(_) => "weak_password",
+            Error::BreachedPassword => "breached_password",
+            Error::PasswordReused(_) => "password_reused",
+            Error::AccountLocked(_) => "account_locked",
+            Error::AccountDisabled(_) => "account_disabled",
+            Error::BadToken(_) => "bad_token",
+            Error::InvalidData(_) => "invalid_data",
+            Error::Corrupt(_) => "corrupt",
+            Error::ReadOnly => "read_only",
+            Error::Io(_) => "io",
+            Error::InvalidConfig(_) => "invalid_config",
+        }
+    }
+
+    /// HTTP status code suggested for responding with this error.
+    /// Errors of the server, such as database failures, are 500.
+    pub fn status_hint(&self) -> u16 {
+        match self {
+            Error::UserNotExist(_) => 404,
+            Error::UserAlreadyExist(_) => 409,
+            Error::BadPass { .. } | Error::WrongPassword(_) | Error::BadToken(_) => 401,
+            Error::InvalidUsername(_) => 400,
+            Error::WeakPassword(_) | Error::BreachedPassword | Error::PasswordReused(_) => 422,
+            Error::AccountLocked(_) => 429,
+            Error::AccountDisabled(_) => 403,
+            Error::ReadOnly => 503,
+            Error::SQL(_)
+            | Error::Crypto(_)
+            | Error::InvalidData(_)
+            | Error::Corrupt(_)
+            | Error::Io(_)
+            | Error::InvalidConfig(_) => 500,
+        }
+    }
+
+    /// Message shown to clients, which is the `Display` one with tokens [`redact`](crate::redact)ed.
+    #[cfg(feature = "serde")]
+    fn public_message(&self) -> String {
+        match self {
+            Error::BadToken(token) => format!("invalid token '{}'", crate::redact(token)),
+            e => e.to_string(),
+        }
+    }
+}
+
+/// Serialized as `{ "code": ..., "message": ... }`, see [`Error::code`],
+/// for example as the body of an HTTP response along with [`Error::status_hint`].
+/// The message is the `Display` one, except that tokens are left out,
+/// but those of errors of the server may still describe the database.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("Error", 2)?;
+        s.serialize_field("code", self.code())?;
+        s.serialize_field("message", &self.public_message())?;
+        s.end()
     }
 }
@@ -0,0 +1,51 @@
+//! Notification of account events, for example to keep an audit trail.
+
+use crate::VerifyOutcome;
+
+/// An account event, passed to the [`Observer`] of a [`SafeBox`](crate::SafeBox).
+/// Tokens are named by their id, see [`redact`](crate::redact).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// A user was created.
+    Created { user: &'a str },
+    /// A user was deleted.
+    Deleted { user: &'a str },
+    /// A password of a user was checked, see [`SafeBox::authenticate`](crate::SafeBox::authenticate).
+    Login {
+        user: &'a str,
+        outcome: VerifyOutcome,
+    },
+    /// A token was issued to a user.
+    TokenIssued { user: &'a str, id: &'a str },
+    /// A token was invalidated, or evicted by [`SafeBox::with_max_tokens_per_user`](crate::SafeBox::with_max_tokens_per_user).
+    TokenInvalidated { user: &'a str, id: &'a str },
+    /// All the tokens of a user were invalidated, such as after a password change.
+    UserTokensInvalidated { user: &'a str, count: usize },
+}
+
+/// Receiver of the account events of a [`SafeBox`](crate::SafeBox),
+/// set with [`SafeBoxBuilder::observer`](crate::SafeBoxBuilder::observer).
+///
+/// It is called synchronously once an operation has taken effect,
+/// including its transaction being committed, so it should return quickly,
+/// for example by sending the event to a channel.
+/// Users changed by [`SafeBox::import`](crate::SafeBox::import) are not reported.
+///
+/// Closures taking an [`Event`] are observers too:
+/// ```
+/// use safe_box::{Event, Observer};
+///
+/// let audit = |e: &Event| println!("{e:?}");
+/// audit.notify(&Event::Created { user: "alice" });
+/// ```
+pub trait Observer: Send + Sync {
+    /// Handle `event`.
+    fn notify(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> Observer for F {
+    fn notify(&self, event: &Event) {
+        self(event)
+    }
+}
@@ -0,0 +1,419 @@
+//! Password hashing with PHC strings.
+//!
+//! The algorithm is chosen at runtime through the [`PasswordHasher`] trait,
+//! with [`Argon2Hasher`] as the default.
+//! If a pepper is configured, passwords are replaced by their HMAC-SHA256 with the pepper
+//! before being hashed.
+//!
+//! With the `zeroize` feature, owned copies of passwords, peppered passwords
+//! and the working memory of Argon2 are scrubbed when dropped.
+//! Salts are not, as they are stored in the clear in PHC strings anyway.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use argon2::{Algorithm, Argon2, Params, PasswordHash, Version};
+use crypto::password_hash::{Output, PasswordVerifier, Salt, SaltString};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+
+use crate::Error;
+
+/// An owned copy of secret material, scrubbed on drop with the `zeroize` feature.
+#[cfg(feature = "zeroize")]
+pub(crate) type Secret<T> = zeroize::Zeroizing<T>;
+#[cfg(not(feature = "zeroize"))]
+pub(crate) type Secret<T> = T;
+
+/// Wrap `v` into a [`Secret`].
+#[cfg(feature = "zeroize")]
+pub(crate) fn secret<T: zeroize::Zeroize>(v: T) -> Secret<T> {
+    zeroize::Zeroizing::new(v)
+}
+
+/// Wrap `v` into a [`Secret`], which does nothing without the `zeroize` feature.
+#[cfg(not(feature = "zeroize"))]
+pub(crate) fn secret<T>(v: T) -> Secret<T> {
+    v
+}
+
+/// Default length of salts in bytes.
+const SALT_LEN: usize = Salt::RECOMMENDED_LENGTH;
+
+/// Range of salt lengths in bytes, limited by Argon2 and by the length of PHC salts.
+const SALT_LENS: RangeInclusive<usize> = argon2::MIN_SALT_LEN..=Salt::MAX_LENGTH * 3 / 4;
+
+/// A random number generator replacing the one of the OS, shared by clones of its owner.
+#[derive(Clone)]
+pub(crate) struct Rng(Arc<Mutex<dyn RngCore + Send>>);
+
+impl Rng {
+    pub fn new(rng: impl RngCore + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(rng)))
+    }
+
+    /// Fill `buf` with random bytes from `rng`, or from the OS if `None`.
+    pub fn fill(rng: Option<&Self>, buf: &mut [u8]) {
+        match rng {
+            Some(rng) => rng.0.lock().unwrap().fill_bytes(buf),
+            None => OsRng.fill_bytes(buf),
+        }
+    }
+}
+
+impl fmt::Debug for Rng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Rng")
+    }
+}
+
+/// Generate a random salt of `len` bytes.
+fn salt(len: usize, rng: Option<&Rng>) -> Result<SaltString, Error> {
+    let mut buf = secret(vec![0u8; len]);
+    Rng::fill(rng, &mut buf);
+    Ok(SaltString::encode_b64(&buf)?)
+}
+
+/// Check that new salts can be `len` bytes long.
+fn check_salt_len(len: usize) -> Result<(), Error> {
+    if !SALT_LENS.contains(&len) {
+        return Err(Error::InvalidConfig(format!(
+            "salt length {len} is not within {SALT_LENS:?}"
+        )));
+    }
+    Ok(())
+}
+
+/// Fail with [`Error::InvalidConfig`] unless Argon2 can output hashes as long as `param` asks.
+pub(crate) fn check_params(param: &Params) -> Result<(), Error> {
+    let len = param.output_len().unwrap_or(Params::DEFAULT_OUTPUT_LEN);
+    if !(Output::MIN_LENGTH..=Output::MAX_LENGTH).contains(&len) {
+        return Err(Error::InvalidConfig(format!(
+            "hash length {len} is not within {}..={}",
+            Output::MIN_LENGTH,
+            Output::MAX_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Identifiers of the Argon2 variants, all of which an [`Argon2Hasher`] verifies.
+const ARGON2_IDS: [&str; 3] = ["argon2id", "argon2i", "argon2d"];
+
+/// Identifier of the algorithm of `phc`, such as `argon2id`, or `None` if it is malformed.
+pub(crate) fn phc_algorithm(phc: &str) -> Option<&str> {
+    PasswordHash::new(phc).ok().map(|p| p.algorithm.as_str())
+}
+
+/// Check `pw` against `phc`, telling a wrong password apart from other errors.
+/// A `phc` that cannot be verified, such as a malformed one, is an [`Error::InvalidData`].
+///
+/// `phc` is checked with `verifier` if made by one of the algorithms `ids`,
+/// and otherwise with the verifier of the other supported algorithm that made it,
+/// so that a database can hold hashes of several algorithms, for example during a migration.
+fn verify_with(
+    verifier: &dyn PasswordVerifier,
+    ids: &[&str],
+    pw: &[u8],
+    phc: &str,
+) -> Result<bool, Error> {
+    use crypto::password_hash::Error as E;
+    let res = PasswordHash::new(phc).and_then(|p| {
+        let alg = p.algorithm.as_str();
+        let argon2 = Argon2::default();
+        let verifier: &dyn PasswordVerifier = if ids.contains(&alg) {
+            verifier
+        } else if ARGON2_IDS.contains(&alg) {
+            &argon2
+        } else {
+            #[cfg(feature = "scrypt")]
+            if alg == scrypt::ALG_ID.as_str() {
+                return p.verify_password(&[&scrypt::Scrypt], pw);
+            }
+            return Err(E::Algorithm);
+        };
+        p.verify_password(&[verifier], pw)
+    });
+    match res {
+        Ok(()) => Ok(true),
+        Err(E::Password) => Ok(false),
+        Err(E::Crypto) => Err(E::Crypto.into()),
+        Err(e) => Err(Error::InvalidData(format!("unusable password hash: {e}"))),
+    }
+}
+
+/// Parameters of a stored password hash, as returned by [`SafeBox::hash_params`](crate::SafeBox::hash_params).
+/// Costs are read from the `m`, `t` and `p` parameters of the PHC string,
+/// which are those of Argon2, and are `None` if missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashParams {
+    /// Identifier of the algorithm, such as `argon2id` or `scrypt`.
+    pub algorithm: String,
+    /// Version of the algorithm, if any.
+    pub version: Option<u32>,
+    /// Memory cost in KiB.
+    pub memory_cost: Option<u32>,
+    /// Number of iterations.
+    pub time_cost: Option<u32>,
+    /// Degree of parallelism.
+    pub parallelism: Option<u32>,
+}
+
+impl HashParams {
+    /// Parse the parameters of `phc`, leaving out its salt and hash.
+    pub(crate) fn parse(phc: &str) -> Result<Self, Error> {
+        let phc = PasswordHash::new(phc)
+            .map_err(|e| Error::InvalidData(format!("unusable password hash: {e}")))?;
+        let get = |name| phc.params.get_decimal(name);
+        Ok(Self {
+            algorithm: phc.algorithm.to_string(),
+            version: phc.version,
+            memory_cost: get("m"),
+            time_cost: get("t"),
+            parallelism: get("p"),
+        })
+    }
+}
+
+/// A password hashing algorithm producing PHC strings.
+pub trait PasswordHasher: Send + Sync {
+    /// Hash `pw` into a PHC string with a fresh salt.
+    fn hash(&self, pw: &[u8]) -> Result<String, Error>;
+
+    /// Check `pw` against the PHC string `phc`.
+    fn verify(&self, pw: &[u8], phc: &str) -> Result<bool, Error>;
+
+    /// Whether `phc` should be hashed again to match this hasher.
+    /// Defaults to never.
+    fn outdated(&self, phc: &str) -> bool {
+        let _ = phc;
+        false
+    }
+}
+
+/// Argon2 hasher, with version 0x13.
+/// Hashes with Argon2id unless another variant is set with [`Argon2Hasher::with_algorithm`].
+#[derive(Debug, Clone)]
+pub struct Argon2Hasher {
+    argon2: Argon2<'static>,
+    algorithm: Algorithm,
+    salt_len: usize,
+    rng: Option<Rng>,
+}
+
+impl Argon2Hasher {
+    /// Create a hasher with the given parameters.
+    pub fn new(param: Params) -> Self {
+        let algorithm = Algorithm::Argon2id;
+        Self {
+            argon2: Argon2::new(algorithm, Version::V0x13, param),
+            algorithm,
+            salt_len: SALT_LEN,
+            rng: None,
+        }
+    }
+
+    /// Hash with the variant `algorithm` instead of Argon2id,
+    /// for example where Argon2i is mandated.
+    ///
+    /// Hashes made with any variant keep verifying,
+    /// but are [outdated](PasswordHasher::outdated) unless made with `algorithm`.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.argon2 = Argon2::new(algorithm, Version::V0x13, self.argon2.params().clone());
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Make salts of `len` bytes instead of 16.
+    /// Fail with [`Error::InvalidConfig`] unless `len` is from 8 to 48 bytes.
+    ///
+    /// Existing hashes keep verifying whatever their salt length, as it is stored along with them.
+    pub fn with_salt_length(mut self, len: usize) -> Result<Self, Error> {
+        check_salt_len(len)?;
+        self.salt_len = len;
+        Ok(self)
+    }
+
+    /// Make salts with `rng` instead of the random number generator of the OS,
+    /// so that tests can produce the same hashes every time.
+    /// Never use a predictable `rng` in production.
+    pub fn with_rng(self, rng: impl RngCore + Send + 'static) -> Self {
+        self.with_shared_rng(Rng::new(rng))
+    }
+
+    /// Like [`Argon2Hasher::with_rng`], with an `rng` also used elsewhere.
+    pub(crate) fn with_shared_rng(mut self, rng: Rng) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+}
+
+impl Default for Argon2Hasher {
+    fn default() -> Self {
+        Self::new(Params::DEFAULT)
+    }
+}
+
+impl PasswordHasher for Argon2Hasher {
+    fn hash(&self, pw: &[u8]) -> Result<String, Error> {
+        let salt = salt(self.salt_len, self.rng.as_ref())?;
+        Ok(PasswordHash::generate(self.argon2.clone(), pw, &salt)?.to_string())
+    }
+
+    fn verify(&self, pw: &[u8], phc: &str) -> Result<bool, Error> {
+        verify_with(&self.argon2, &ARGON2_IDS, pw, phc)
+    }
+
+    fn outdated(&self, phc: &str) -> bool {
+        let Ok(phc) = PasswordHash::new(phc) else {
+            return true;
+        };
+        if phc.algorithm != self.algorithm.ident() || phc.version != Some(Version::V0x13.into()) {
+            return true;
+        }
+        let Ok(p) = Params::try_from(&phc) else {
+            return true;
+        };
+        let cur = self.argon2.params();
+        let len = |p: &Params| p.output_len().unwrap_or(Params::DEFAULT_OUTPUT_LEN);
+        p.m_cost() != cur.m_cost()
+            || p.t_cost() != cur.t_cost()
+            || p.p_cost() != cur.p_cost()
+            || len(&p) != len(cur)
+    }
+}
+
+/// scrypt hasher.
+#[cfg(feature = "scrypt")]
+#[derive(Debug, Clone, Copy)]
+pub struct ScryptHasher {
+    param: scrypt::Params,
+    salt_len: usize,
+}
+
+#[cfg(feature = "scrypt")]
+impl ScryptHasher {
+    /// Create a hasher with the given parameters.
+    pub fn new(param: scrypt::Params) -> Self {
+        Self {
+            param,
+            salt_len: SALT_LEN,
+        }
+    }
+
+    /// See [`Argon2Hasher::with_salt_length`].
+    pub fn with_salt_length(mut self, len: usize) -> Result<Self, Error> {
+        check_salt_len(len)?;
+        self.salt_len = len;
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "scrypt")]
+impl Default for ScryptHasher {
+    fn default() -> Self {
+        Self::new(scrypt::Params::recommended())
+    }
+}
+
+#[cfg(feature = "scrypt")]
+impl PasswordHasher for ScryptHasher {
+    fn hash(&self, pw: &[u8]) -> Result<String, Error> {
+        use crypto::password_hash::PasswordHasher;
+        let salt = salt(self.salt_len, None)?;
+        let p = scrypt::Scrypt.hash_password_customized(pw, None, None, self.param, &salt)?;
+        Ok(p.to_string())
+    }
+
+    fn verify(&self, pw: &[u8], phc: &str) -> Result<bool, Error> {
+        verify_with(&scrypt::Scrypt, &[scrypt::ALG_ID.as_str()], pw, phc)
+    }
+
+    fn outdated(&self, phc: &str) -> bool {
+        let Ok(phc) = PasswordHash::new(phc) else {
+            return true;
+        };
+        if phc.algorithm != scrypt::ALG_ID {
+            return true;
+        }
+        let Ok(p) = scrypt::Params::try_from(&phc) else {
+            return true;
+        };
+        p.log_n() != self.param.log_n() || p.r() != self.param.r() || p.p() != self.param.p()
+    }
+}
+
+/// Hashers verifying the stored hashes of other algorithms, by PHC identifier.
+pub(crate) type Readers = HashMap<String, Box<dyn PasswordHasher>>;
+
+/// Hashes new passwords and verifies stored ones, applying the pepper.
+pub(crate) struct Hasher {
+    inner: Box<dyn PasswordHasher>,
+    readers: Arc<Readers>,
+    pepper: Option<Hmac<Sha256>>,
+}
+
+impl Hasher {
+    pub fn new(inner: Box<dyn PasswordHasher>, readers: Readers, pepper: Option<&[u8]>) -> Self {
+        let pepper =
+            pepper.map(|p| Hmac::new_from_slice(p).expect("HMAC accepts keys of any length"));
+        Self {
+            inner,
+            readers: Arc::new(readers),
+            pepper,
+        }
+    }
+
+    /// A hasher with the same readers and pepper, hashing with `inner`.
+    pub fn with_inner(&self, inner: Box<dyn PasswordHasher>) -> Self {
+        Self {
+            inner,
+            readers: self.readers.clone(),
+            pepper: self.pepper.clone(),
+        }
+    }
+
+    /// Call `f` with the bytes actually hashed for `pass`.
+    fn prepare<T>(&self, pass: &[u8], f: impl FnOnce(&[u8]) -> T) -> T {
+        match &self.pepper {
+            Some(mac) => {
+                let mut mac = mac.clone();
+                mac.update(pass);
+                let pw = secret(mac.finalize().into_bytes().to_vec());
+                f(&pw)
+            }
+            None => f(pass),
+        }
+    }
+
+    /// Hash `pass` into a PHC string.
+    pub fn hash(&self, pass: &[u8]) -> Result<String, Error> {
+        self.prepare(pass, |pw| self.inner.hash(pw))
+    }
+
+    /// Check `pass` against the PHC string `phc`,
+    /// with the reader of its algorithm if any and with the inner hasher otherwise.
+    pub fn verify(&self, phc: &str, pass: &[u8]) -> Result<bool, Error> {
+        let reader = phc_algorithm(phc).and_then(|alg| self.readers.get(alg));
+        let verifier = reader.unwrap_or(&self.inner);
+        self.prepare(pass, |pw| verifier.verify(pw, phc))
+    }
+
+    /// A key for `label` derived from the pepper, if any.
+    #[cfg(feature = "totp")]
+    pub fn derive_key(&self, label: &[u8]) -> Option<Secret<[u8; 32]>> {
+        let mut mac = self.pepper.clone()?;
+        mac.update(label);
+        Some(secret(mac.finalize().into_bytes().into()))
+    }
+
+    /// Whether `phc` was hashed differently than new passwords are.
+    pub fn outdated(&self, phc: &str) -> bool {
+        self.inner.outdated(phc)
+    }
+}
@@ -1,148 +1,2287 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "breach-check")]
+mod breach;
+mod builder;
+mod clock;
+pub mod config;
+mod db;
 pub mod err;
+mod event;
+mod hasher;
+mod meta;
+pub mod metric;
+mod reset;
+mod schema;
+mod sign;
+mod store;
+mod strength;
+mod token;
+#[cfg(feature = "totp")]
+mod totp;
+mod tx;
 
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    ops::DerefMut,
-    path::Path,
-    sync::RwLock,
-    time::{Duration, SystemTime},
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use argon2::{Argon2, Params, PasswordHash};
-use async_mutex::Mutex as AsyncMutex;
+#[cfg(not(feature = "postgres"))]
+use std::path::Path;
+
 use base64::Engine;
-use crypto::password_hash::SaltString;
-use getrandom::getrandom;
-use rand_core::OsRng;
-use sqlx::{query, sqlite::SqliteConnectOptions, Connection, Row, SqliteConnection};
+use db::{Connection, Db, Pool};
+use futures_util::{stream, Stream, TryStreamExt};
+#[cfg(feature = "scrypt")]
+pub use hasher::ScryptHasher;
+use hasher::{phc_algorithm, secret, Hasher};
+pub use hasher::{Argon2Hasher, HashParams, PasswordHasher};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, query_scalar, Executor, Row};
+use token::token_id;
+use tracing::{trace, warn};
+use unicode_normalization::UnicodeNormalization;
+
+/// Milliseconds since the UNIX epoch, as timestamps are stored in the database.
+fn unix_ms(t: SystemTime) -> i64 {
+    let ms = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    i64::try_from(ms).unwrap_or(i64::MAX)
+}
+
+/// Inverse of [`unix_ms`].
+fn from_unix_ms(ms: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64)
+}
+
+/// Error of a login of `user` with `outcome`, which is neither authenticated nor a wrong password.
+fn login_error(user: &str, outcome: VerifyOutcome) -> Error {
+    let user = user.to_owned();
+    match outcome {
+        VerifyOutcome::Locked => Error::AccountLocked(user),
+        VerifyOutcome::Disabled => Error::AccountDisabled(user),
+        _ => Error::UserNotExist(user),
+    }
+}
+
+fn token_key(token: &str) -> TokenKey {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Textual form of a [`TokenKey`] stored in the database.
+fn key_str(key: &TokenKey) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key)
+}
+
+/// Inverse of [`key_str`].
+fn key_from_str(s: &str) -> Result<TokenKey, Error> {
+    let v = base64::engine::general_purpose::STANDARD
+        .decode(s)
+        .map_err(|_| Error::InvalidData(format!("malformed token key '{s}'")))?;
+    v.try_into()
+        .map_err(|_| Error::InvalidData(format!("malformed token key '{s}'")))
+}
+
+/// Run the CPU-heavy `f` on a thread of tokio meant for blocking code,
+/// so that it does not stall other tasks.
+#[cfg(feature = "tokio")]
+async fn offload<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("blocking task panicked")
+}
+
+/// Run the CPU-heavy `f` in place, without the `tokio` feature.
+#[cfg(not(feature = "tokio"))]
+async fn offload<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    f()
+}
+
+/// Textual form of `scopes` stored in the database and signed tokens.
+fn join_scopes(scopes: &[String]) -> String {
+    scopes.join(" ")
+}
+
+/// Inverse of [`join_scopes`], also accepting `NULL`.
+fn split_scopes(s: Option<&str>) -> Vec<String> {
+    s.unwrap_or_default()
+        .split(' ')
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Check that `scopes` can be stored, as they are separated by spaces.
+fn validate_scopes(scopes: &[String]) -> Result<(), Error> {
+    match scopes
+        .iter()
+        .find(|s| s.is_empty() || s.contains(char::is_whitespace))
+    {
+        Some(s) => Err(Error::InvalidConfig(format!("invalid token scope '{s}'"))),
+        None => Ok(()),
+    }
+}
+
+/// Public information about an active token.
+#[derive(Debug, Clone)]
+pub struct TokenInfo {
+    /// Start of the token, made of its first 4 characters,
+    /// or empty if the token is too short to reveal any, see [`redact`].
+    pub id: String,
+    /// When the token was issued.
+    pub issued: SystemTime,
+    /// Scopes granted to the token, see [`SafeBox::issue_token_scoped`].
+    pub scopes: Vec<String>,
+    /// Remaining lifetime of the token.
+    pub ttl: Duration,
+}
+
+/// Statistics of a [`SafeBox`], returned by [`SafeBox::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SafeStats {
+    /// Number of tokens held in memory, see [`SafeBox::token_count`].
+    pub tokens: usize,
+    /// Number of open connections to the database.
+    pub connections: u32,
+    /// Number of open connections not in use.
+    pub idle_connections: usize,
+}
 
-fn salt() -> SaltString {
-    SaltString::generate(OsRng)
+/// A user and the hash of its password, as exported by [`SafeBox::export`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UserRecord {
+    /// Name of the user.
+    pub user: String,
+    /// PHC string of the password hash.
+    pub phc: String,
 }
 
-fn gen_token() -> String {
-    let mut buf = [0u8; 32];
-    getrandom(&mut buf).unwrap();
-    base64::engine::general_purpose::STANDARD.encode(buf)
+/// Outcome of checking a password with [`SafeBox::authenticate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    /// The password is correct.
+    Authenticated,
+    /// The password is wrong.
+    WrongPassword,
+    /// The user does not exist.
+    UserNotFound,
+    /// The user is locked out after too many failed attempts, see [`SafeBox::with_lockout`].
+    Locked,
+    /// The user is disabled, see [`SafeBox::disable`].
+    Disabled,
 }
 
+/// What [`SafeBox::import`] does with a user that already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Fail with [`Error::UserAlreadyExist`], importing nothing.
+    Fail,
+    /// Keep the existing user.
+    Skip,
+    /// Replace the password hash of the existing user.
+    Overwrite,
+}
+
+/// How tokens are stored and verified.
+enum TokenMode {
+    /// Tokens are kept in memory only.
+    Memory,
+    /// Tokens are kept in memory and also stored in the database.
+    Persistent,
+    /// Tokens are signed and verified without being stored anywhere.
+    Signed(sign::Key),
+}
+
+/// Policy for locking users out after repeated failed password checks.
+#[derive(Debug, Clone, Copy)]
+struct Lockout {
+    max_failures: u32,
+    window: Duration,
+    cooldown: Duration,
+}
+
+/// What [`SafeBox::verify_detailed`] tells about a user after a successful login,
+/// to decide whether to ask for more, such as a TOTP code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginContext {
+    /// Whether the user has a TOTP secret, see `SafeBox::set_totp` with the `totp` feature.
+    pub totp: bool,
+    /// When the user last logged in before, if ever, see [`SafeBox::last_login`].
+    pub last_login: Option<SystemTime>,
+    /// Whether the stored hash was made with other parameters than the current ones.
+    /// It is then replaced, unless the `SafeBox` is read-only.
+    pub outdated_hash: bool,
+    /// Whether the password is a temporary one set by [`SafeBox::admin_reset`],
+    /// or the user must change it for another reason, see [`SafeBox::set_must_change`].
+    pub must_change_password: bool,
+}
+
+/// The stored row of a user whose password was checked.
+struct Account {
+    phc: String,
+    algorithm: Option<String>,
+    last_login: Option<i64>,
+    totp: bool,
+    must_change: bool,
+}
+
+/// Failed password checks of a user.
+struct Failure {
+    /// Failures since the last successful check, see [`SafeBox::failed_attempts`].
+    total: u32,
+    /// Failures within the lockout window starting at `since`.
+    count: u32,
+    since: SystemTime,
+    /// When the user was locked out, for the cooldown of the lockout policy.
+    locked_at: Option<SystemTime>,
+}
+
+/// Check run on new passwords, returning the reason for rejecting one.
+type PasswordPolicy = Box<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
 /// Interface to the password database.
+///
+/// A `SafeBox` is shared between tasks by wrapping it in an [`Arc`].
+/// Helper tasks that should not keep the database open can hold a [`Weak`](std::sync::Weak)
+/// from [`Arc::downgrade`] instead, like `spawn_expiry` does with the `tokio` feature.
 pub struct SafeBox {
-    conn: AsyncMutex<SqliteConnection>,
-    param: Params,
-    token: RwLock<HashMap<String, (String, SystemTime)>>,
+    pool: Pool,
+    tables: Tables,
+    /// Hasher of new passwords, replaced by [`SafeBox::set_hasher`].
+    hasher: RwLock<Arc<Hasher>>,
+    token: Box<dyn TokenStore>,
+    token_ttl: Duration,
+    token_mode: TokenMode,
+    token_gen: Box<dyn TokenGen>,
+    clock: Box<dyn Clock>,
+    observer: Option<Box<dyn Observer>>,
+    max_user_token: Option<usize>,
+    case_insensitive: bool,
+    max_username_len: usize,
+    lockout: Option<Lockout>,
+    failure: Mutex<HashMap<String, Failure>>,
+    /// Failed TOTP codes, counted apart so that a right password does not reset them.
+    #[cfg(feature = "totp")]
+    totp_failure: Mutex<HashMap<String, Failure>>,
+    policy: Option<PasswordPolicy>,
+    #[cfg(feature = "breach-check")]
+    breach: Option<BreachList>,
+    /// Number of previous passwords a user cannot reuse.
+    history: usize,
+    /// Algorithm passwords are hashed again with on login, see [`SafeBox::with_migration_target`].
+    migration_target: Option<String>,
+    /// Hash checked instead of those of missing users, see [`SafeBoxBuilder::hide_missing_users`].
+    dummy: RwLock<Option<String>>,
+    /// Password reset tokens, by digest.
+    /// with the hash of the password they reset.
+    reset: Mutex<HashMap<TokenKey, (TokenEntry, String)>>,
+    reset_ttl: Duration,
+    read_only: bool,
+    /// Number of users, see [`SafeBoxBuilder::cache_user_cnt`].
+    user_cnt: Option<AtomicUsize>,
 }
 
-pub use err::SafeBoxError as Error;
+#[cfg(feature = "breach-check")]
+pub use breach::BreachList;
+pub use builder::SafeBoxBuilder;
+pub use clock::{Clock, SystemClock};
+pub use config::SafeConfig;
+pub use err::Error;
+#[allow(deprecated)]
+pub use err::SafeBoxError;
+pub use event::{Event, Observer};
+pub use schema::SCHEMA_VERSION;
+pub use store::{MemoryTokenStore, TokenEntry, TokenKey, TokenStore};
+pub use strength::{password_entropy, password_strength, Strength};
+pub use token::{redact, RandomTokenGen, Token, TokenGen, CHANGE_PASSWORD_SCOPE};
+pub use tx::Tx;
+
+/// Default maximum number of connections to the database.
+const MAX_CONNECTIONS: u32 = 10;
+
+/// Default time an SQLite connection waits for a locked database.
+#[cfg(not(feature = "postgres"))]
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
-/// Initialize the database.
-const Q_INIT: &str = "CREATE TABLE IF NOT EXISTS main (user TEXT PRIMARY KEY, phc TEXT);";
+/// Number of users fetched per query by [`SafeBox::verify_many`].
+const VERIFY_CHUNK: usize = 500;
+
+/// Number of users fetched per query by [`SafeBox::stream_users`].
+const STREAM_PAGE: i64 = 1000;
+
+/// Default maximum length of usernames, in characters.
+const MAX_USERNAME_LEN: usize = 255;
+
+/// Default lifetime of tokens issued without an explicit TTL.
+const TOKEN_TTL: Duration = Duration::from_secs(300);
+
+/// Default name of the table of users.
+const TABLE: &str = "main";
+
+/// Columns added to `main` after its initial schema, with their types.
+const MAIN_COLUMNS: &[(&str, &str)] = &[
+    ("last_login", "BIGINT"),
+    ("disabled", "BOOLEAN NOT NULL DEFAULT FALSE"),
+    ("totp_secret", "TEXT"),
+    ("hash_algo", "TEXT"),
+    ("created_at", "BIGINT"),
+    ("must_change", "BOOLEAN NOT NULL DEFAULT FALSE"),
+];
+
+/// Columns added to the table of previous password hashes after its initial schema, with their types.
+const HISTORY_COLUMNS: &[(&str, &str)] = &[("seq", "BIGINT")];
+
+/// Columns added to the table of tokens after its initial schema, with their types.
+const TOKEN_COLUMNS: &[(&str, &str)] = &[("scopes", "TEXT")];
+
+/// Names of the tables of a user store.
+struct Tables {
+    main: String,
+    history: String,
+    token: String,
+    meta: String,
+    schema: String,
+}
+
+impl Default for Tables {
+    fn default() -> Self {
+        Self {
+            main: TABLE.to_owned(),
+            history: "password_history".to_owned(),
+            token: "token".to_owned(),
+            meta: "meta".to_owned(),
+            schema: "schema_version".to_owned(),
+        }
+    }
+}
+
+impl Tables {
+    /// Tables of the store named `name`,
+    /// which are `<name>`, `<name>_password_history`, `<name>_token`, `<name>_meta`
+    /// and `<name>_schema_version`.
+    /// The default store keeps the names `main`, `password_history`, `token`, `meta`
+    /// and `schema_version`.
+    ///
+    /// Names are quoted in queries, so they may be SQL keywords,
+    /// and lowercased, which is how Postgres stored them before they were quoted.
+    /// Names that would share a table with the default store or with another store are refused.
+    fn new(name: &str) -> Result<Self, Error> {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(Error::InvalidConfig(format!("invalid table name '{name}'")));
+        }
+        if name == TABLE {
+            return Ok(Self::default());
+        }
+        let name = name.to_ascii_lowercase();
+        let d = Self::default();
+        let taken = [&d.main, &d.history, &d.token, &d.meta, &d.schema].contains(&&name);
+        let suffix = ["_password_history", "_token", "_meta", "_schema_version"]
+            .iter()
+            .any(|s| name.ends_with(s));
+        if taken || suffix {
+            return Err(Error::InvalidConfig(format!(
+                "table name '{name}' is used by another store"
+            )));
+        }
+        Ok(Self {
+            history: format!("{name}_password_history"),
+            token: format!("{name}_token"),
+            meta: format!("{name}_meta"),
+            schema: format!("{name}_schema_version"),
+            main: name,
+        })
+    }
+
+    /// Initialize the table of users.
+    fn init(&self) -> String {
+        format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}" ("user" TEXT PRIMARY KEY, phc TEXT);"#,
+            self.main
+        )
+    }
+
+    /// Initialize the table of previous password hashes.
+    fn init_history(&self) -> String {
+        format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}" ("user" TEXT, phc TEXT, changed_at BIGINT);"#,
+            self.history
+        )
+    }
+
+    /// Initialize the table of user attributes.
+    fn init_meta(&self) -> String {
+        format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}" ("user" TEXT, "key" TEXT, value TEXT, PRIMARY KEY ("user", "key"));"#,
+            self.meta
+        )
+    }
+
+    /// Initialize the table of applied schema versions, see [`SCHEMA_VERSION`].
+    fn init_schema(&self) -> String {
+        format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}" (version BIGINT NOT NULL);"#,
+            self.schema
+        )
+    }
+
+    /// Initialize the table of persisted tokens.
+    /// Only the digest and redacted form of a token are stored.
+    fn init_token(&self) -> String {
+        format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}" (token TEXT PRIMARY KEY, id TEXT, "user" TEXT, issued_at BIGINT, expire_at BIGINT);"#,
+            self.token
+        )
+    }
+}
+
+/// A summary of the configuration and state, leaving out tokens, hashes and keys.
+/// Only the locks of the token store are taken, briefly and one at a time.
+impl fmt::Debug for SafeBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token_mode = match self.token_mode {
+            TokenMode::Memory => "memory",
+            TokenMode::Persistent => "persistent",
+            TokenMode::Signed(_) => "signed",
+        };
+        f.debug_struct("SafeBox")
+            .field("table", &self.tables.main)
+            .field("token_mode", &token_mode)
+            .field("tokens", &self.token.len())
+            .field("token_ttl", &self.token_ttl)
+            .field("connections", &self.pool.size())
+            .field("read_only", &self.read_only)
+            .field("case_insensitive", &self.case_insensitive)
+            .field("lockout", &self.lockout)
+            .finish_non_exhaustive()
+    }
+}
 
 impl SafeBox {
-    /// Open an SQLite connection with specified database file and create a `SafeBox`.
+    /// Open an SQLite connection pool with specified database file and create a `SafeBox`
+    /// with the default configuration, see [`SafeBox::builder`] for others.
+    /// Not available with the `postgres` feature, see [`SafeBox::connect`].
     /// # Example
-    /// ```
+    /// ```no_run
     /// use safe_box::SafeBox;
     ///
-    /// let safe = SafeBox::new("secure.db").await.unwrap();
+    /// # async fn example() -> Result<(), safe_box::Error> {
+    /// let safe = SafeBox::new("secure.db").await?;
+    /// # Ok(())
+    /// # }
     /// ```
+    #[cfg(not(feature = "postgres"))]
     pub async fn new(p: impl AsRef<Path>) -> Result<Self, Error> {
-        let opt = SqliteConnectOptions::default()
-            .filename(p)
-            .create_if_missing(true);
-        let mut conn = SqliteConnection::connect_with(&opt).await?;
-        query(Q_INIT).execute(&mut conn).await?;
-        Ok(Self {
-            conn: AsyncMutex::new(conn),
-            param: Params::DEFAULT,
-            token: RwLock::new(HashMap::new()),
-        })
+        Self::builder().build(p).await
     }
 
-    /// Instantantiate a hasher with `self.param`.
-    fn hasher(&self) -> Argon2<'static> {
-        Argon2::new(
-            argon2::Algorithm::Argon2id,
-            argon2::Version::V0x13,
-            self.param.clone(),
-        )
+    /// Like [`SafeBox::new`], but a corrupt database file, for example truncated on power loss,
+    /// is backed up and replaced by a fresh one, see [`SafeBoxBuilder::build_or_recreate`].
+    /// All users of the corrupt database are then lost.
+    ///
+    /// Opening a corrupt database otherwise fails with [`Error::Corrupt`].
+    #[cfg(not(feature = "postgres"))]
+    pub async fn open_or_recreate(p: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::builder().build_or_recreate(p).await
+    }
+
+    /// Open the existing SQLite database file at `p` read-only, see [`SafeBoxBuilder::read_only`].
+    /// Not available with the `postgres` feature.
+    #[cfg(not(feature = "postgres"))]
+    pub async fn open_readonly(p: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::builder().read_only().build(p).await
+    }
+
+    /// Create a `SafeBox` backed by an SQLite database in memory, which is lost when it is dropped.
+    /// This is mostly useful for tests.
+    ///
+    /// The database lives in a single connection, which is thus never closed.
+    /// Not available with the `postgres` feature.
+    #[cfg(not(feature = "postgres"))]
+    pub async fn new_in_memory() -> Result<Self, Error> {
+        Self::builder().build_in_memory().await
+    }
+
+    /// Like [`SafeBox::new`], but users are stored in `table` instead of `main`,
+    /// see [`SafeBoxBuilder::table`].
+    #[cfg(not(feature = "postgres"))]
+    pub async fn with_table(p: impl AsRef<Path>, table: &str) -> Result<Self, Error> {
+        Self::builder().table(table).build(p).await
+    }
+
+    /// Connect to the database at `url` and create a `SafeBox` with the default configuration.
+    /// This is a `postgres://` URL with the `postgres` feature, and an `sqlite://` URL otherwise.
+    /// # Example
+    /// ```no_run
+    /// use safe_box::SafeBox;
+    ///
+    /// # async fn example() -> Result<(), safe_box::Error> {
+    /// let safe = SafeBox::connect("postgres://localhost/accounts").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        Self::builder().connect(url).await
+    }
+
+    /// Create a `SafeBox` with the default configuration on the connection pool of the application,
+    /// which avoids contending for the lock of an SQLite database with a second pool.
+    /// See [`SafeBoxBuilder::build_from_pool`].
+    /// # Example
+    /// ```no_run
+    /// use safe_box::SafeBox;
+    /// # #[cfg(not(feature = "postgres"))]
+    /// use sqlx::SqlitePool as Pool;
+    /// # #[cfg(feature = "postgres")]
+    /// # use sqlx::PgPool as Pool;
+    ///
+    /// # async fn example() -> Result<(), safe_box::Error> {
+    /// let pool = Pool::connect("sqlite://app.db").await?;
+    /// sqlx::query("CREATE TABLE IF NOT EXISTS profile (user TEXT, bio TEXT)")
+    ///     .execute(&pool)
+    ///     .await?;
+    /// let safe = SafeBox::from_pool(pool.clone()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn from_pool(pool: Pool) -> Result<Self, Error> {
+        Self::builder().build_from_pool(pool).await
+    }
+
+    /// Create a [`SafeBoxBuilder`] to configure the `SafeBox` before opening the database.
+    /// # Example
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// use safe_box::SafeBox;
+    ///
+    /// # #[cfg(not(feature = "postgres"))]
+    /// # async fn example() -> Result<(), safe_box::Error> {
+    /// let safe = SafeBox::builder()
+    ///     .memory_cost(64 * 1024)
+    ///     .time_cost(3)
+    ///     .token_ttl(Duration::from_secs(600))
+    ///     .case_insensitive(true)
+    ///     .build("secure.db")
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> SafeBoxBuilder {
+        SafeBoxBuilder::default()
+    }
+
+    /// Create a `SafeBox` as described by `config`.
+    pub async fn from_config(config: SafeConfig) -> Result<Self, Error> {
+        let mut builder = Self::builder();
+        let argon2 = config.argon2.unwrap_or_default();
+        if let Some(algorithm) = &argon2.algorithm {
+            let algorithm = algorithm.parse().map_err(|_| {
+                Error::InvalidConfig(format!("unknown Argon2 variant '{algorithm}'"))
+            })?;
+            builder = builder.argon2_algorithm(algorithm);
+        }
+        if let Some(m_cost) = argon2.memory_cost {
+            builder = builder.memory_cost(m_cost);
+        }
+        if let Some(t_cost) = argon2.time_cost {
+            builder = builder.time_cost(t_cost);
+        }
+        if let Some(p_cost) = argon2.parallelism {
+            builder = builder.parallelism(p_cost);
+        }
+        if let Some(len) = argon2.hash_length {
+            builder = builder.hash_length(len);
+        }
+        if let Some(len) = argon2.salt_length {
+            builder = builder.salt_length(len);
+        }
+        if let Some(secs) = config.token_ttl_secs {
+            builder = builder.token_ttl(Duration::from_secs(secs));
+        }
+        if let Some(target) = &config.migration_target {
+            builder = builder.migration_target(target);
+        }
+        if let Some(lockout) = config.lockout {
+            builder = builder.lockout(
+                lockout.max_failures,
+                Duration::from_secs(lockout.window_secs),
+                Duration::from_secs(lockout.cooldown_secs),
+            );
+        }
+        #[cfg(not(feature = "postgres"))]
+        return builder.build(&config.database).await;
+        #[cfg(feature = "postgres")]
+        return builder.connect(&config.database).await;
+    }
+
+    /// Like [`SafeBox::new`], but tokens are also stored in the database,
+    /// see [`SafeBoxBuilder::persistent_tokens`].
+    #[cfg(not(feature = "postgres"))]
+    pub async fn new_persistent_tokens(p: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::builder().persistent_tokens().build(p).await
+    }
+
+    /// Like [`SafeBox::new`], but tokens are signed with `secret` instead of being stored,
+    /// see [`SafeBoxBuilder::signed_tokens`].
+    #[cfg(not(feature = "postgres"))]
+    pub async fn new_signed(p: impl AsRef<Path>, secret: &[u8]) -> Result<Self, Error> {
+        Self::builder().signed_tokens(secret).build(p).await
+    }
+
+    /// Like [`SafeBox::connect`], but tokens are also stored in the database,
+    /// see [`SafeBoxBuilder::persistent_tokens`].
+    pub async fn connect_persistent_tokens(url: &str) -> Result<Self, Error> {
+        Self::builder().persistent_tokens().connect(url).await
+    }
+
+    /// Like [`SafeBox::connect`], but tokens are signed with `secret` instead of being stored,
+    /// see [`SafeBoxBuilder::signed_tokens`].
+    pub async fn connect_signed(url: &str, secret: &[u8]) -> Result<Self, Error> {
+        Self::builder().signed_tokens(secret).connect(url).await
+    }
+
+    /// Set the lifetime of tokens issued by [`SafeBox::issue_token`] and [`SafeBox::verify`].
+    /// Defaults to 5 minutes.
+    pub fn with_token_ttl(mut self, ttl: Duration) -> Self {
+        self.token_ttl = ttl;
+        self
+    }
+
+    /// Limit the number of tokens a user can hold at the same time.
+    /// When the limit is reached, issuing a new token evicts the oldest one.
+    /// A limit of 0 is treated as 1.
+    pub fn with_max_tokens_per_user(mut self, max: usize) -> Self {
+        self.max_user_token = Some(max.max(1));
+        self
+    }
+
+    /// Treat usernames case-insensitively.
+    /// Usernames are then stored in their canonical form,
+    /// which is their NFKC normalization in lowercase.
+    ///
+    /// Enabling this on a database with usernames that are not canonical
+    /// makes those users unreachable.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Refuse to create users, or rename them, with usernames longer than `max` characters
+    /// once in their canonical form, failing with [`Error::InvalidUsername`].
+    /// Defaults to 255.
+    ///
+    /// Empty usernames and those with control characters, such as NUL, are always refused.
+    pub fn with_max_username_len(mut self, max: usize) -> Self {
+        self.max_username_len = max;
+        self
+    }
+
+    /// Lock a user out for `cooldown` after `max_failures` failed password checks within `window`.
+    /// A locked user fails with [`Error::AccountLocked`] without the password being checked.
+    /// A successful check resets the count.
+    pub fn with_lockout(mut self, max_failures: u32, window: Duration, cooldown: Duration) -> Self {
+        self.lockout = Some(Lockout {
+            max_failures,
+            window,
+            cooldown,
+        });
+        self
+    }
+
+    /// Check new passwords with `policy` before hashing them.
+    /// A rejected password fails with [`Error::WeakPassword`] carrying the returned reason.
+    /// By default any password is accepted.
+    pub fn with_password_policy(
+        mut self,
+        policy: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Remember the last `n` passwords of each user and refuse to [`SafeBox::update`] to them,
+    /// counting the current one.
+    /// Defaults to 0, which disables the check.
+    pub fn with_password_history(mut self, n: usize) -> Self {
+        self.history = n;
+        self
+    }
+
+    /// Migrate passwords to the hash algorithm `algorithm`, such as `argon2id` or `scrypt`:
+    /// the password of a user whose hash was made with another algorithm
+    /// is hashed again when it next logs in, see [`SafeBox::migration_progress`].
+    /// By default only [outdated](PasswordHasher::outdated) hashes are.
+    ///
+    /// New hashes are made by the current hasher, see [`SafeBox::set_hasher`],
+    /// which must therefore make hashes of `algorithm`.
+    pub fn with_migration_target(mut self, algorithm: &str) -> Self {
+        self.migration_target = Some(algorithm.to_owned());
+        self
+    }
+
+    /// Refuse new passwords that are in `list`, failing with [`Error::BreachedPassword`].
+    ///
+    /// The list is read from disk on each new password, while the caller waits.
+    #[cfg(feature = "breach-check")]
+    pub fn with_breach_list(mut self, list: BreachList) -> Self {
+        self.breach = Some(list);
+        self
+    }
+
+    /// Check a new password against the breach list and the policy,
+    /// which sees invalid UTF-8 as replacement characters.
+    fn validate(&self, pass: &[u8]) -> Result<(), Error> {
+        #[cfg(feature = "breach-check")]
+        if let Some(list) = &self.breach {
+            if list.contains(pass)? {
+                return Err(Error::BreachedPassword);
+            }
+        }
+        match &self.policy {
+            Some(policy) => policy(&String::from_utf8_lossy(pass)).map_err(Error::WeakPassword),
+            None => Ok(()),
+        }
+    }
+
+    /// Hash new passwords with `hasher` from now on, without reopening the database.
+    /// Stored hashes keep verifying as long as `hasher` understands them,
+    /// and are replaced when their users next log in if `hasher` finds them outdated.
+    ///
+    /// With [`SafeBoxBuilder::hide_missing_users`], the dummy hash is made again with `hasher`,
+    /// so that missing users still take as long as wrong passwords.
+    pub async fn set_hasher(&self, hasher: impl PasswordHasher + 'static) -> Result<(), Error> {
+        let hasher = Arc::new(self.hasher().with_inner(Box::new(hasher)));
+        let dummy = if self.dummy.read().unwrap().is_some() {
+            let hasher = hasher.clone();
+            let pass = RandomTokenGen.generate();
+            Some(offload(move || hasher.hash(pass.as_bytes())).await?)
+        } else {
+            None
+        };
+        *self.hasher.write().unwrap() = hasher;
+        *self.dummy.write().unwrap() = dummy;
+        Ok(())
+    }
+
+    /// Hash new passwords with Argon2 and `params` from now on, for example to raise the cost
+    /// on a running service, see [`SafeBox::set_hasher`].
+    /// The variant is Argon2id and salts have the default length, like with [`Argon2Hasher::new`].
+    /// Fail with [`Error::InvalidConfig`] if the hash length of `params` is not supported.
+    pub async fn set_argon2_params(&self, params: argon2::Params) -> Result<(), Error> {
+        hasher::check_params(&params)?;
+        self.set_hasher(Argon2Hasher::new(params)).await
+    }
+
+    /// The current hasher, see [`SafeBox::set_hasher`].
+    fn hasher(&self) -> Arc<Hasher> {
+        self.hasher.read().unwrap().clone()
+    }
+
+    /// Hash `pass` into a PHC string, see [`offload`].
+    async fn hash(&self, pass: &[u8]) -> Result<String, Error> {
+        let (hasher, pass) = (self.hasher(), secret(pass.to_owned()));
+        offload(move || hasher.hash(&pass)).await
+    }
+
+    /// Check `pass` against the PHC string `phc`, see [`offload`].
+    async fn verify_hash(&self, phc: &str, pass: &[u8]) -> Result<bool, Error> {
+        let (hasher, phc, pass) = (self.hasher(), phc.to_owned(), secret(pass.to_owned()));
+        offload(move || hasher.verify(&phc, &pass)).await
+    }
+
+    /// The form of `user` used in the database.
+    fn canon<'a>(&self, user: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(user.nfkc().collect::<String>().to_lowercase())
+        } else {
+            Cow::Borrowed(user)
+        }
+    }
+
+    /// Check a new username, which is already canonical, see [`SafeBox::with_max_username_len`].
+    fn validate_user(&self, user: &str) -> Result<(), Error> {
+        let reason = if user.is_empty() {
+            "username is empty".to_owned()
+        } else if user.chars().count() > self.max_username_len {
+            format!(
+                "username is longer than {} characters",
+                self.max_username_len
+            )
+        } else if user.chars().any(char::is_control) {
+            "username contains control characters".to_owned()
+        } else {
+            return Ok(());
+        };
+        Err(Error::InvalidUsername(reason))
+    }
+
+    /// Pass `event` to the observer, if any.
+    fn emit(&self, event: Event) {
+        if let Some(observer) = &self.observer {
+            observer.notify(&event);
+        }
+    }
+
+    /// Whether tokens are also stored in the database.
+    fn persist(&self) -> bool {
+        matches!(self.token_mode, TokenMode::Persistent) && !self.read_only
+    }
+
+    /// Fail with [`Error::ReadOnly`] if the `SafeBox` is read-only.
+    fn writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
     }
 
     /// Create new user entry with `user`name and `pass`word.
     pub async fn create(&self, user: &str, pass: &str) -> Result<(), Error> {
-        let q = query("SELECT NULL FROM main WHERE user = ?").bind(user);
-        let v = q.fetch_all(self.conn.lock().await.deref_mut()).await?;
-        if v.len() > 0 {
-            return Err(Error::UserAlreadyExist(user.to_owned()));
-        }
-        let p = PasswordHash::generate(self.hasher(), pass, &salt())?.to_string();
-        let q = query("INSERT INTO main (user, phc) VALUES (?, ?)")
+        self.create_bytes(user, pass.as_bytes()).await
+    }
+
+    /// Like [`SafeBox::create`], with a password that need not be valid UTF-8,
+    /// such as key material derived by the client.
+    pub async fn create_bytes(&self, user: &str, pass: &[u8]) -> Result<(), Error> {
+        self.create_report(user, pass).await.map(|_| ())
+    }
+
+    /// Like [`SafeBox::create_bytes`], also returning the parameters the password was hashed with,
+    /// so that provisioning tools can check that the expected cost was applied.
+    pub async fn create_report(
+        &self,
+        user: &str,
+        pass: impl AsRef<[u8]>,
+    ) -> Result<HashParams, Error> {
+        let pass = pass.as_ref();
+        let user = &*self.canon(user);
+        self.validate_user(user)?;
+        self.validate(pass)?;
+        let p = self.hash(pass).await?;
+        let params = HashParams::parse(&p)?;
+        self.insert(&self.pool, user, &p).await?;
+        self.count_users(1, 0);
+        self.emit(Event::Created { user });
+        Ok(params)
+    }
+
+    /// Insert `user`, which is already canonical, with the password hash `phc`.
+    async fn insert<'e>(
+        &self,
+        e: impl Executor<'e, Database = Db>,
+        user: &str,
+        phc: &str,
+    ) -> Result<(), Error> {
+        self.writable()?;
+        let q = format!(
+            r#"INSERT INTO "{}" ("user", phc, hash_algo, created_at) VALUES ($1, $2, $3, $4)"#,
+            self.tables.main
+        );
+        let q = query(&q)
             .bind(user)
-            .bind(p);
-        q.execute(self.conn.lock().await.deref_mut()).await?;
+            .bind(phc)
+            .bind(phc_algorithm(phc))
+            .bind(unix_ms(self.clock.now()));
+        match q.execute(e).await {
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                Err(Error::UserAlreadyExist(user.to_owned()))
+            }
+            Err(e) => Err(e.into()),
+            Ok(_) => {
+                metric::count(metric::USER_CREATED, 1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Begin a transaction, to make several changes at once
+    /// along with queries on other tables through [`Tx::conn`].
+    /// # Example
+    /// ```no_run
+    /// # async fn example(safe: safe_box::SafeBox) -> Result<(), safe_box::Error> {
+    /// let mut tx = safe.transaction().await?;
+    /// tx.create("alice", "password").await?;
+    /// sqlx::query("INSERT INTO profile (user) VALUES ('alice')")
+    ///     .execute(tx.conn())
+    ///     .await?;
+    /// tx.commit().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn transaction(&self) -> Result<Tx<'_>, Error> {
+        Ok(Tx::new(self, self.pool.begin().await?))
+    }
+
+    /// Create many users in a single transaction.
+    /// If any username already exists, no user is created
+    /// and the error names the first conflicting one.
+    pub async fn create_many(&self, users: &[(String, String)]) -> Result<(), Error> {
+        let mut rows = Vec::with_capacity(users.len());
+        for (user, pass) in users {
+            let user = self.canon(user);
+            self.validate_user(&user)?;
+            self.validate(pass.as_bytes())?;
+            let p = self.hash(pass.as_bytes()).await?;
+            rows.push((user, p));
+        }
+        let mut tx = self.pool.begin().await?;
+        for (user, p) in &rows {
+            self.insert(&mut *tx, user, p).await?;
+        }
+        tx.commit().await?;
+        self.count_users(rows.len(), 0);
+        for (user, _) in &rows {
+            self.emit(Event::Created { user });
+        }
         Ok(())
     }
 
-    /// Verify the provided `user`name and `pass`word.
-    /// Return a new token if successful.
-    pub async fn verify(&self, user: &str, pass: &str) -> Result<String, Error> {
-        let query = query("SELECT phc FROM main WHERE user = ?").bind(user);
-        let mut conn = self.conn.lock().await;
-        let v = query.fetch_all(conn.deref_mut()).await?;
+    /// Export every user with its password hash, sorted by username.
+    pub async fn export(&self) -> Result<Vec<UserRecord>, Error> {
+        let q = format!(
+            r#"SELECT "user", phc FROM "{}" ORDER BY "user""#,
+            self.tables.main
+        );
+        let v = query(&q).fetch_all(&self.pool).await?;
+        v.iter()
+            .map(|row| {
+                Ok(UserRecord {
+                    user: row.try_get("user")?,
+                    phc: row.try_get("phc")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Import users exported by [`SafeBox::export`] in a single transaction,
+    /// handling existing usernames according to `on_conflict`.
+    /// Fail with [`Error::InvalidData`] if a hash is not a valid PHC string.
+    ///
+    /// The hashes are stored as is, so they must have been made with the same pepper, if any.
+    pub async fn import(
+        &self,
+        records: &[UserRecord],
+        on_conflict: OnConflict,
+    ) -> Result<(), Error> {
+        self.writable()?;
+        let conflict = match on_conflict {
+            OnConflict::Fail => "",
+            OnConflict::Skip => r#" ON CONFLICT ("user") DO NOTHING"#,
+            OnConflict::Overwrite => {
+                r#" ON CONFLICT ("user") DO UPDATE SET phc = excluded.phc, hash_algo = excluded.hash_algo"#
+            }
+        };
+        let q = format!(
+            r#"INSERT INTO "{}" ("user", phc, hash_algo, created_at) VALUES ($1, $2, $3, $4){conflict}"#,
+            self.tables.main
+        );
+        let mut tx = self.pool.begin().await?;
+        for r in records {
+            let user = self.canon(&r.user);
+            self.validate_user(&user)?;
+            if argon2::PasswordHash::new(&r.phc).is_err() {
+                return Err(Error::InvalidData(format!("malformed hash of '{user}'")));
+            }
+            let q = query(&q)
+                .bind(&*user)
+                .bind(&r.phc)
+                .bind(phc_algorithm(&r.phc))
+                .bind(unix_ms(self.clock.now()));
+            match q.execute(&mut *tx).await {
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                    return Err(Error::UserAlreadyExist(user.into_owned()))
+                }
+                res => res?,
+            };
+        }
+        tx.commit().await?;
+        if self.user_cnt.is_some() {
+            // Overwritten users cannot be told apart from new ones, so count them again.
+            self.user_cnt().await?;
+        }
+        Ok(())
+    }
+
+    /// Check the provided `user`name and `pass`word against the stored hash.
+    /// Return the row of the user if the password matches.
+    async fn check<'e>(
+        &self,
+        e: impl Executor<'e, Database = Db>,
+        user: &str,
+        pass: &[u8],
+    ) -> Result<Option<Account>, Error> {
+        let user = &*self.canon(user);
+        let now = self.clock.now();
+        if self.is_locked(user, now) {
+            return Err(Error::AccountLocked(user.to_owned()));
+        }
+        let account = self.check_hash(e, user, pass).await?;
+        self.record_check(user, account.is_some(), now);
+        Ok(account)
+    }
+
+    /// Number of failed password checks of `user` since the last successful one,
+    /// whether or not a lockout policy is set.
+    /// With the `totp` feature, this adds the failed TOTP codes since the last right one,
+    /// see `SafeBox::verify_totp`.
+    /// Failures are only counted in memory, and not shared between instances.
+    pub fn failed_attempts(&self, user: &str) -> u32 {
+        let user = &*self.canon(user);
+        let total = |map: &Mutex<HashMap<String, Failure>>| {
+            map.lock().unwrap().get(user).map_or(0, |f| f.total)
+        };
+        let n = total(&self.failure);
+        #[cfg(feature = "totp")]
+        let n = n.saturating_add(total(&self.totp_failure));
+        n
+    }
+
+    /// Whether `user`, which is already canonical, is locked out at `now`,
+    /// after too many failed password checks or TOTP codes.
+    fn is_locked(&self, user: &str, now: SystemTime) -> bool {
+        #[cfg(feature = "totp")]
+        if self.locked_in(&self.totp_failure, user, now) {
+            return true;
+        }
+        self.locked_in(&self.failure, user, now)
+    }
+
+    /// Whether the failures of `user` in `map` lock it out at `now`.
+    fn locked_in(
+        &self,
+        map: &Mutex<HashMap<String, Failure>>,
+        user: &str,
+        now: SystemTime,
+    ) -> bool {
+        let Some(lockout) = self.lockout else {
+            return false;
+        };
+        let map = map.lock().unwrap();
+        let locked_at = map.get(user).and_then(|f| f.locked_at);
+        // compare elapsed times rather than adding durations to `now`,
+        // which would overflow with a cooldown such as `Duration::MAX`
+        locked_at.is_some_and(|t| now.duration_since(t).map_or(true, |d| d < lockout.cooldown))
+    }
+
+    /// Count a password check of `user` made at `now` towards the lockout policy.
+    fn record_check(&self, user: &str, ok: bool, now: SystemTime) {
+        self.record_in(&self.failure, user, ok, now);
+    }
+
+    /// Count a check of `user` made at `now` in `map`, towards the lockout policy.
+    fn record_in(
+        &self,
+        map: &Mutex<HashMap<String, Failure>>,
+        user: &str,
+        ok: bool,
+        now: SystemTime,
+    ) {
+        let mut map = map.lock().unwrap();
+        if ok {
+            map.remove(user);
+            return;
+        }
+        let f = map.entry(user.to_owned()).or_insert(Failure {
+            total: 0,
+            count: 0,
+            since: now,
+            locked_at: None,
+        });
+        f.total = f.total.saturating_add(1);
+        let Some(lockout) = self.lockout else {
+            return;
+        };
+        let expired = now
+            .duration_since(f.since)
+            .is_ok_and(|d| d > lockout.window);
+        if f.locked_at.is_some() || expired {
+            f.count = 0;
+            f.since = now;
+            f.locked_at = None;
+        }
+        f.count += 1;
+        if f.count >= lockout.max_failures {
+            f.locked_at = Some(now);
+            trace!("locked out '{user}'");
+        }
+    }
+
+    /// Check `pass` against the stored hash of `user`, which is already canonical.
+    /// Return the row of the user if the password matches.
+    async fn check_hash<'e>(
+        &self,
+        e: impl Executor<'e, Database = Db>,
+        user: &str,
+        pass: &[u8],
+    ) -> Result<Option<Account>, Error> {
+        let q = format!(
+            r#"SELECT phc, hash_algo, disabled, last_login, totp_secret IS NOT NULL AS totp, must_change FROM "{}" WHERE "user" = $1"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user);
+        let v = q.fetch_all(e).await?;
         match v.len() {
             0 => return Err(Error::UserNotExist(user.to_owned())),
             2.. => return Err(Error::InvalidData(format!("duplicate user '{user}'"))),
             _ => (),
         };
-        let p = v[0].try_get("phc")?;
-        let p = PasswordHash::new(p)?;
-        let res = p.verify_password(&[&self.hasher()], pass);
-        if let Err(crypto::password_hash::Error::Password) = res {
+        if v[0].try_get("disabled")? {
+            return Err(Error::AccountDisabled(user.to_owned()));
+        }
+        let phc: String = v[0].try_get("phc")?;
+        if !self.verify_hash(&phc, pass).await? {
+            return Ok(None);
+        }
+        Ok(Some(Account {
+            phc,
+            algorithm: v[0].try_get("hash_algo")?,
+            last_login: v[0].try_get("last_login")?,
+            totp: v[0].try_get("totp")?,
+            must_change: v[0].try_get("must_change")?,
+        }))
+    }
+
+    /// Replace the outdated hash `old` of `user` with a new hash of `pass`.
+    /// `pass` must already be known to be correct.
+    async fn rehash(&self, user: &str, pass: &[u8], old: &str) -> Result<(), Error> {
+        let p = self.hash(pass).await?;
+        let q = format!(
+            r#"UPDATE "{}" SET phc = $1, hash_algo = $2 WHERE "user" = $3 AND phc = $4"#,
+            self.tables.main
+        );
+        let q = query(&q)
+            .bind(&p)
+            .bind(phc_algorithm(&p))
+            .bind(user)
+            .bind(old);
+        q.execute(&self.pool).await?;
+        trace!("rehashed password of '{user}'");
+        Ok(())
+    }
+
+    /// Like [`SafeBox::check`], but a wrong password is an [`Error::BadPass`].
+    async fn check_pass<'e>(
+        &self,
+        e: impl Executor<'e, Database = Db>,
+        user: &str,
+        pass: &[u8],
+    ) -> Result<(), Error> {
+        if self.check(e, user, pass).await?.is_none() {
             return Err(Error::BadPass {
                 user: user.to_owned(),
-                pass: pass.to_owned(),
             });
         }
-        res?;
-        let token = gen_token();
+        Ok(())
+    }
+
+    /// Verify the provided `user`name and `pass`word.
+    /// Return a new token if successful, or `None` if the password is wrong.
+    /// Other outcomes of [`SafeBox::authenticate`] are errors.
+    ///
+    /// This is the single call a login handler needs:
+    /// there is no need to call [`SafeBox::issue_token`] after it.
+    ///
+    /// If the user must change its password, see [`SafeBox::set_must_change`],
+    /// the token only has the scope [`CHANGE_PASSWORD_SCOPE`],
+    /// which the handler can tell with [`SafeBox::verify_token_scoped`]
+    /// to only let the user change its password.
+    pub async fn verify(&self, user: &str, pass: &str) -> Result<Option<Token>, Error> {
+        self.verify_bytes(user, pass.as_bytes()).await
+    }
+
+    /// Like [`SafeBox::verify`], with a password that need not be valid UTF-8,
+    /// see [`SafeBox::create_bytes`].
+    pub async fn verify_bytes(&self, user: &str, pass: &[u8]) -> Result<Option<Token>, Error> {
+        let user = &*self.canon(user);
+        match self.login(user, pass).await? {
+            (VerifyOutcome::Authenticated, Some((account, _))) if account.must_change => {
+                let scopes = [CHANGE_PASSWORD_SCOPE.to_owned()];
+                Ok(Some(self.issue_token_scoped(user, &scopes).await?))
+            }
+            (VerifyOutcome::Authenticated, _) => Ok(Some(self.issue_token(user).await?)),
+            (VerifyOutcome::WrongPassword, _) => Ok(None),
+            (outcome, _) => Err(login_error(user, outcome)),
+        }
+    }
+
+    /// Verify the provided `user`name and `pass`word without issuing a token.
+    /// Errors are only returned for failures to check the password,
+    /// such as the database being unreachable.
+    ///
+    /// If the stored hash was made with other parameters than the current ones,
+    /// the password is hashed again and stored, failures of which are only logged.
+    ///
+    /// With [`SafeBoxBuilder::hide_missing_users`], a missing user is a [`VerifyOutcome::WrongPassword`].
+    pub async fn authenticate(&self, user: &str, pass: &str) -> Result<VerifyOutcome, Error> {
+        self.authenticate_bytes(user, pass.as_bytes()).await
+    }
+
+    /// Like [`SafeBox::authenticate`], with a password that need not be valid UTF-8.
+    async fn authenticate_bytes(&self, user: &str, pass: &[u8]) -> Result<VerifyOutcome, Error> {
+        Ok(self.login(user, pass).await?.0)
+    }
+
+    /// Verify the provided `user`name and `pass`word like [`SafeBox::authenticate`],
+    /// without issuing a token, so that the caller can first ask for more,
+    /// such as a TOTP code, depending on the returned context.
+    /// Return `None` if the password is wrong,
+    /// and fail like [`SafeBox::verify`] for other outcomes.
+    ///
+    /// The context is read along with the password hash, costing no other query.
+    pub async fn verify_detailed(
+        &self,
+        user: &str,
+        pass: &str,
+    ) -> Result<Option<LoginContext>, Error> {
+        let user = &*self.canon(user);
+        match self.login(user, pass.as_bytes()).await? {
+            (VerifyOutcome::Authenticated, Some((account, outdated))) => Ok(Some(LoginContext {
+                totp: account.totp,
+                last_login: account.last_login.map(from_unix_ms),
+                outdated_hash: outdated,
+                must_change_password: account.must_change,
+            })),
+            (VerifyOutcome::WrongPassword, _) => Ok(None),
+            (outcome, _) => Err(login_error(user, outcome)),
+        }
+    }
+
+    /// See [`SafeBox::authenticate`].
+    /// Also return the row of the user and whether its hash was outdated if authenticated.
+    async fn login(
+        &self,
+        user: &str,
+        pass: &[u8],
+    ) -> Result<(VerifyOutcome, Option<(Account, bool)>), Error> {
+        let user = &*self.canon(user);
+        let dummy = self.dummy.read().unwrap().clone();
+        let (outcome, account) = match self.check(&self.pool, user, pass).await {
+            Ok(Some(account)) => (VerifyOutcome::Authenticated, Some(account)),
+            Ok(None) => (VerifyOutcome::WrongPassword, None),
+            Err(Error::UserNotExist(_)) => match dummy {
+                Some(phc) => {
+                    // spend as long as for a wrong password
+                    self.verify_hash(&phc, pass).await?;
+                    (VerifyOutcome::WrongPassword, None)
+                }
+                None => (VerifyOutcome::UserNotFound, None),
+            },
+            Err(Error::AccountLocked(_)) => (VerifyOutcome::Locked, None),
+            Err(Error::AccountDisabled(_)) => (VerifyOutcome::Disabled, None),
+            Err(e) => return Err(e),
+        };
+        metric::verify(outcome);
+        self.emit(Event::Login { user, outcome });
+        let Some(account) = account else {
+            return Ok((outcome, None));
+        };
+        let migrating =
+            (self.migration_target.as_ref()).is_some_and(|t| account.algorithm.as_ref() != Some(t));
+        let outdated = migrating || self.hasher().outdated(&account.phc);
+        if self.read_only {
+            return Ok((outcome, Some((account, outdated))));
+        }
+        if outdated {
+            if let Err(e) = self.rehash(user, pass, &account.phc).await {
+                warn!("failed to rehash password of '{user}': {e}");
+            }
+        }
+        let q = format!(
+            r#"UPDATE "{}" SET last_login = $1 WHERE "user" = $2"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(unix_ms(self.clock.now())).bind(user);
+        q.execute(&self.pool).await?;
+        Ok((outcome, Some((account, outdated))))
+    }
+
+    /// Check many `(user, password)` pairs at once, returning their outcomes in order.
+    /// The hashes are fetched in few queries and checked on several threads.
+    ///
+    /// Failures count towards the lockout policy like with [`SafeBox::authenticate`],
+    /// but outdated hashes are not updated and the login time is not recorded.
+    /// With [`SafeBoxBuilder::hide_missing_users`], missing users are wrong passwords,
+    /// checked against the dummy hash like with [`SafeBox::authenticate`].
+    pub async fn verify_many(
+        &self,
+        creds: &[(String, String)],
+    ) -> Result<Vec<VerifyOutcome>, Error> {
+        let users: Vec<_> = creds.iter().map(|(user, _)| self.canon(user)).collect();
+        let mut unique: Vec<&str> = users.iter().map(|user| &**user).collect();
+        unique.sort_unstable();
+        unique.dedup();
+        let mut rows = HashMap::new();
+        for chunk in unique.chunks(VERIFY_CHUNK) {
+            let params = (1..=chunk.len())
+                .map(|i| format!("${i}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let q = format!(
+                r#"SELECT "user", phc, disabled FROM "{}" WHERE "user" IN ({params})"#,
+                self.tables.main
+            );
+            let mut q = query(&q);
+            for user in chunk {
+                q = q.bind(*user);
+            }
+            for row in q.fetch_all(&self.pool).await? {
+                let user: String = row.try_get("user")?;
+                let entry = (
+                    row.try_get::<String, _>("phc")?,
+                    row.try_get::<bool, _>("disabled")?,
+                );
+                if rows.insert(user.clone(), entry).is_some() {
+                    return Err(Error::InvalidData(format!("duplicate user '{user}'")));
+                }
+            }
+        }
+        let now = self.clock.now();
+        let dummy = self.dummy.read().unwrap().clone();
+        let mut outcome = Vec::with_capacity(creds.len());
+        let mut jobs = vec![];
+        for (i, (user, (_, pass))) in users.iter().zip(creds).enumerate() {
+            let res = match rows.get(&**user) {
+                None => match &dummy {
+                    Some(phc) => {
+                        // spend as long as for a wrong password
+                        jobs.push((i, phc.clone(), secret(pass.clone())));
+                        VerifyOutcome::WrongPassword
+                    }
+                    None => VerifyOutcome::UserNotFound,
+                },
+                Some((_, true)) => VerifyOutcome::Disabled,
+                Some(_) if self.is_locked(user, now) => VerifyOutcome::Locked,
+                Some((phc, false)) => {
+                    jobs.push((i, phc.clone(), secret(pass.clone())));
+                    VerifyOutcome::WrongPassword
+                }
+            };
+            outcome.push(res);
+        }
+        let hasher = self.hasher();
+        let checked = offload(move || {
+            let hasher = &*hasher;
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let size = jobs.len().div_ceil(threads).max(1);
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = jobs
+                    .chunks(size)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|(i, phc, pass)| {
+                                    Ok((*i, hasher.verify(phc, pass.as_bytes())?))
+                                })
+                                .collect::<Result<Vec<_>, Error>>()
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("password verification panicked"))
+                    .collect::<Result<Vec<_>, Error>>()
+            })
+        })
+        .await?;
+        for (i, ok) in checked.into_iter().flatten() {
+            if !rows.contains_key(&*users[i]) {
+                continue;
+            }
+            self.record_check(&users[i], ok, now);
+            if ok {
+                outcome[i] = VerifyOutcome::Authenticated;
+            }
+        }
+        for (user, o) in users.iter().zip(&outcome) {
+            metric::verify(*o);
+            self.emit(Event::Login { user, outcome: *o });
+        }
+        Ok(outcome)
+    }
+
+    /// Issue a new token for `user` without checking any password.
+    /// Fail with [`Error::UserNotExist`] if the user does not exist,
+    /// and [`Error::AccountDisabled`] if the user is disabled.
+    ///
+    /// A token issued while the user is concurrently deleted is invalidated before this returns,
+    /// so that no token outlives its user, except signed ones.
+    pub async fn issue_token(&self, user: &str) -> Result<Token, Error> {
+        self.issue_token_ttl(user, self.token_ttl).await
+    }
+
+    /// Issue a new token for `user` that expires after `ttl`.
+    /// Fail with [`Error::InvalidConfig`] if `ttl` is too long to represent its expiry.
+    pub async fn issue_token_ttl(&self, user: &str, ttl: Duration) -> Result<Token, Error> {
+        self.issue(user, ttl, &[]).await
+    }
+
+    /// Issue a new token for `user` granted `scopes`, such as roles or permissions,
+    /// which [`SafeBox::verify_token_scoped`] returns along with the user.
+    /// Fail with [`Error::InvalidConfig`] if a scope is empty or contains whitespace.
+    ///
+    /// Scopes are only recorded, what they allow is up to the caller.
+    /// Signed tokens carry their scopes, which are thus visible to clients.
+    pub async fn issue_token_scoped(&self, user: &str, scopes: &[String]) -> Result<Token, Error> {
+        validate_scopes(scopes)?;
+        self.issue(user, self.token_ttl, scopes).await
+    }
+
+    async fn issue(&self, user: &str, ttl: Duration, scopes: &[String]) -> Result<Token, Error> {
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"SELECT disabled FROM "{}" WHERE "user" = $1"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user);
+        match q.fetch_optional(&self.pool).await? {
+            None => return Err(Error::UserNotExist(user.to_owned())),
+            Some(row) if row.try_get("disabled")? => {
+                return Err(Error::AccountDisabled(user.to_owned()))
+            }
+            Some(_) => (),
+        }
+        let token = self.token_gen.generate();
+        let key = token_key(&token);
+        let id = token_id(&token);
+        let issued = self.clock.now();
+        let expire = issued
+            .checked_add(ttl)
+            .ok_or_else(|| Error::InvalidConfig(format!("token lifetime {ttl:?} is too long")))?;
+        if let TokenMode::Signed(key) = &self.token_mode {
+            let claims = sign::Claims {
+                user: user.to_owned(),
+                issued,
+                expire,
+                scopes: scopes.to_vec(),
+            };
+            let token = sign::encode(key, &claims);
+            trace!("issued signed token {} to '{user}'", redact(&token));
+            metric::count(metric::TOKEN_ISSUED, 1);
+            let id = token_id(&token);
+            self.emit(Event::TokenIssued { user, id: &id });
+            return Ok(Token::from(token));
+        }
+        if self.persist() {
+            let q = format!(
+                r#"INSERT INTO "{}" (token, id, "user", issued_at, expire_at, scopes) VALUES ($1, $2, $3, $4, $5, $6)"#,
+                self.tables.token
+            );
+            let q = query(&q)
+                .bind(key_str(&key))
+                .bind(&id)
+                .bind(user)
+                .bind(unix_ms(issued))
+                .bind(unix_ms(expire))
+                .bind(join_scopes(scopes));
+            q.execute(&self.pool).await?;
+        }
+        trace!("issued token {id}** to '{user}'");
+        metric::count(metric::TOKEN_ISSUED, 1);
+        self.emit(Event::TokenIssued { user, id: &id });
+        let entry = TokenEntry {
+            id,
+            user: user.to_owned(),
+            issued,
+            expire,
+            scopes: scopes.to_vec(),
+        };
+        let evicted = match self.max_user_token {
+            Some(max) => self.evict_oldest(user, max - 1),
+            None => vec![],
+        };
+        self.token.insert(key, entry);
+        metric::count(metric::TOKEN_INVALIDATED, evicted.len() as u64);
+        for (k, e) in evicted {
+            trace!("evicted token {}** of '{user}'", e.id);
+            self.emit(Event::TokenInvalidated { user, id: &e.id });
+            if self.persist() {
+                let q = format!(r#"DELETE FROM "{}" WHERE token = $1"#, self.tables.token);
+                let q = query(&q).bind(key_str(&k));
+                q.execute(&self.pool).await?;
+            }
+        }
+        // A user deleted since it was looked up has had its tokens invalidated
+        // after the deletion was committed, so either this token was among them
+        // or the user is seen missing now.
+        if !self.exists(user).await? {
+            self.invalidate_token(&token).await?;
+            return Err(Error::UserNotExist(user.to_owned()));
+        }
+        Ok(Token::from(token))
+    }
+
+    /// Remove the oldest tokens of `user` until at most `keep` are left.
+    /// Return the keys and entries of the removed tokens.
+    ///
+    /// Tokens issued concurrently to the same user may briefly exceed `keep`.
+    fn evict_oldest(&self, user: &str, keep: usize) -> Vec<(TokenKey, TokenEntry)> {
+        let mut own = self.token.user_tokens(user);
+        if own.len() <= keep {
+            return vec![];
+        }
+        own.sort_by_key(|(k, e)| (e.issued, *k));
+        own.truncate(own.len() - keep);
+        own.into_iter()
+            .filter_map(|(k, _)| self.token.remove(&k).map(|e| (k, e)))
+            .collect()
+    }
+
+    /// List the active tokens of `user`.
+    pub fn active_tokens(&self, user: &str) -> Vec<TokenInfo> {
+        let user = &*self.canon(user);
+        let now = self.clock.now();
         self.token
-            .write()
+            .user_tokens(user)
+            .into_iter()
+            .filter(|(_, e)| now < e.expire)
+            .map(|(_, e)| TokenInfo {
+                id: e.id,
+                issued: e.issued,
+                scopes: e.scopes,
+                ttl: e.expire.duration_since(now).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Number of tokens held in memory, including expired ones not removed yet
+    /// by [`SafeBox::expire_token`]. Always 0 with signed tokens.
+    pub fn token_count(&self) -> usize {
+        self.token.len()
+    }
+
+    /// Get the user, issue time and remaining lifetime of the provided `token`.
+    /// Return `None` if the token is invalid or expired, like [`SafeBox::verify_token`].
+    pub fn token_info(&self, token: impl AsRef<str>) -> Option<(String, SystemTime, Duration)> {
+        let token = token.as_ref().trim_ascii();
+        let now = self.clock.now();
+        if let TokenMode::Signed(key) = &self.token_mode {
+            let c = sign::decode(key, token)?;
+            let ttl = c.expire.duration_since(now).ok().filter(|d| !d.is_zero())?;
+            return Some((c.user, c.issued, ttl));
+        }
+        let e = self.token.get(&token_key(token))?;
+        let ttl = e.expire.duration_since(now).ok().filter(|d| !d.is_zero())?;
+        Some((e.user, e.issued, ttl))
+    }
+
+    /// Keep the provided `token` alive for the token lifetime from now on,
+    /// see [`SafeBox::with_token_ttl`], unless it would already live longer.
+    /// Calling this whenever a token is used makes sessions expire only when idle.
+    ///
+    /// Return whether the token is valid, but signed tokens are never extended.
+    pub async fn touch_token(&self, token: impl AsRef<str>) -> Result<bool, Error> {
+        if let TokenMode::Signed(_) = self.token_mode {
+            return Ok(false);
+        }
+        let key = token_key(token.as_ref().trim_ascii());
+        let now = self.clock.now();
+        let Some(e) = self.token.get(&key).filter(|e| now < e.expire) else {
+            return Ok(false);
+        };
+        let expire = now
+            .checked_add(self.token_ttl)
+            .map_or(e.expire, |t| t.max(e.expire));
+        if !self.token.set_expire(&key, expire) {
+            return Ok(false);
+        }
+        if self.persist() {
+            let q = format!(
+                r#"UPDATE "{}" SET expire_at = $1 WHERE token = $2"#,
+                self.tables.token
+            );
+            let q = query(&q).bind(unix_ms(expire)).bind(key_str(&key));
+            q.execute(&self.pool).await?;
+        }
+        Ok(true)
+    }
+
+    /// Invalidate the provided `token`, ignoring surrounding whitespace like [`SafeBox::verify_token`].
+    /// Has no effect on signed tokens.
+    pub async fn invalidate_token(&self, token: impl AsRef<str>) -> Result<(), Error> {
+        let token = token.as_ref().trim_ascii();
+        let key = token_key(token);
+        if let Some(e) = self.token.remove(&key) {
+            metric::count(metric::TOKEN_INVALIDATED, 1);
+            self.emit(Event::TokenInvalidated {
+                user: &e.user,
+                id: &e.id,
+            });
+        }
+        trace!("invalidated token {}", redact(token));
+        if self.persist() {
+            let q = format!(r#"DELETE FROM "{}" WHERE token = $1"#, self.tables.token);
+            let q = query(&q).bind(key_str(&key));
+            q.execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    /// Invalidate all tokens that belong to `user`, including its password reset tokens.
+    /// Has no effect on signed tokens.
+    ///
+    /// This is called whenever a user is deleted or renamed,
+    /// so that a reset token cannot take over another user later created with the same name.
+    pub async fn invalidate_user_token(&self, user: &str) -> Result<(), Error> {
+        let user = &*self.canon(user);
+        self.reset
+            .lock()
             .unwrap()
-            .insert(token.clone(), (user.to_owned(), SystemTime::now()));
-        Ok(token)
+            .retain(|_, (e, _)| e.user != user);
+        let removed = self.token.remove_user(user);
+        metric::count(metric::TOKEN_INVALIDATED, removed as u64);
+        trace!("invalidated all tokens of '{user}'");
+        if removed > 0 {
+            self.emit(Event::UserTokensInvalidated {
+                user,
+                count: removed,
+            });
+        }
+        if self.persist() {
+            let q = format!(r#"DELETE FROM "{}" WHERE "user" = $1"#, self.tables.token);
+            let q = query(&q).bind(user);
+            q.execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove all expired tokens.
+    pub async fn expire_token(&self) -> Result<(), Error> {
+        let now = self.clock.now();
+        self.token.remove_expired(now);
+        if self.persist() {
+            let q = format!(
+                r#"DELETE FROM "{}" WHERE expire_at <= $1"#,
+                self.tables.token
+            );
+            let q = query(&q).bind(unix_ms(now));
+            q.execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+
+    /// Spawn a task that calls [`SafeBox::expire_token`] and [`SafeBox::expire_reset_token`]
+    /// `every` once in a while.
+    /// The task only holds a weak reference and stops after the `SafeBox` is dropped.
+    #[cfg(feature = "tokio")]
+    pub fn spawn_expiry(
+        self: &std::sync::Arc<Self>,
+        every: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let safe = std::sync::Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                let Some(safe) = safe.upgrade() else {
+                    break;
+                };
+                if let Err(e) = safe.expire_token().await {
+                    tracing::warn!("failed to expire tokens: {e}");
+                }
+                safe.expire_reset_token();
+            }
+        })
     }
 
     /// Verify the provided `token`.
     /// Returns the user it belongs to if valid.
-    pub fn verify_token(&self, token: &str) -> Result<String, Error> {
-        let map = self.token.read().unwrap();
-        if let Some((s, t)) = map.get(token) {
-            let now = SystemTime::now();
-            if let Ok(d) = now.duration_since(*t) {
-                if d < Duration::from_secs(300) {
-                    return Ok(s.to_owned());
-                }
+    /// An expired token is removed from memory.
+    ///
+    /// Tokens are looked up by their SHA-256 digest rather than compared directly.
+    /// An attacker measuring response times can thus learn at most how many leading bytes
+    /// of the digest of a guessed token match a stored one,
+    /// which does not help to construct a valid token.
+    ///
+    /// ASCII whitespace around `token`, such as left when copying it from a header, is ignored,
+    /// as tokens never start or end with it:
+    /// ```
+    /// # #[cfg(not(feature = "postgres"))]
+    /// # tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+    /// # let safe = safe_box::SafeBox::new_in_memory().await?;
+    /// # safe.create("alice", "password").await?;
+    /// let token = safe.issue_token("alice").await?;
+    /// assert_eq!(safe.verify_token(format!(" {token} "))?, "alice");
+    /// # Ok::<_, safe_box::Error>(())
+    /// # }).unwrap();
+    /// ```
+    pub fn verify_token(&self, token: impl AsRef<str>) -> Result<String, Error> {
+        self.verify_token_scoped(token).map(|(user, _)| user)
+    }
+
+    /// Like [`SafeBox::verify_token`], also returning the scopes of the token,
+    /// see [`SafeBox::issue_token_scoped`].
+    pub fn verify_token_scoped(
+        &self,
+        token: impl AsRef<str>,
+    ) -> Result<(String, Vec<String>), Error> {
+        let (user, scopes, _) = self.lookup_token(token.as_ref(), self.clock.now())?;
+        Ok((user, scopes))
+    }
+
+    /// Like [`SafeBox::verify_token`], also returning the time left until the token expires,
+    /// so that clients can log in again before it does.
+    pub fn verify_token_full(&self, token: impl AsRef<str>) -> Result<(String, Duration), Error> {
+        let now = self.clock.now();
+        let (user, _, expire) = self.lookup_token(token.as_ref(), now)?;
+        Ok((user, expire.duration_since(now).unwrap_or_default()))
+    }
+
+    /// Get the user, scopes and expiry of `token` if it is valid at `now`.
+    /// An expired token is removed, see [`SafeBox::verify_token`].
+    fn lookup_token(
+        &self,
+        token: &str,
+        now: SystemTime,
+    ) -> Result<(String, Vec<String>, SystemTime), Error> {
+        let token = token.trim_ascii();
+        if let TokenMode::Signed(key) = &self.token_mode {
+            return match sign::decode(key, token) {
+                Some(c) if now < c.expire => Ok((c.user, c.scopes, c.expire)),
+                _ => Err(Error::BadToken(token.to_owned())),
+            };
+        }
+        let key = token_key(token);
+        match self.token.get(&key) {
+            Some(e) if now < e.expire => Ok((e.user, e.scopes, e.expire)),
+            Some(_) => {
+                self.token.remove(&key);
+                Err(Error::BadToken(token.to_owned()))
             }
+            None => Err(Error::BadToken(token.to_owned())),
         }
-        Err(Error::BadToken(token.to_owned()))
     }
 
     /// Update a user's password to `new`.
+    /// Fail with [`Error::PasswordReused`] if `new` is among the remembered passwords,
+    /// see [`SafeBox::with_password_history`],
+    /// and with [`Error::UserNotExist`] if `user` does not exist,
+    /// even if it is removed concurrently after its password was checked.
     pub async fn update(&self, user: &str, pass: &str, new: &str) -> Result<(), Error> {
-        self.verify(user, pass).await?;
-        let p = PasswordHash::generate(self.hasher(), new, &salt())?.to_string();
-        let q = query("UPDATE main SET phc = ? WHERE user = ?")
-            .bind(p)
-            .bind(user);
-        q.execute(self.conn.lock().await.deref_mut()).await?;
+        self.update_bytes(user, pass.as_bytes(), new.as_bytes())
+            .await
+    }
+
+    /// Like [`SafeBox::update`], with passwords that need not be valid UTF-8,
+    /// see [`SafeBox::create_bytes`].
+    pub async fn update_bytes(&self, user: &str, pass: &[u8], new: &[u8]) -> Result<(), Error> {
+        let user = &*self.canon(user);
+        self.check_pass(&self.pool, user, pass).await?;
+        self.validate(new)?;
+        let p = self.hash(new).await?;
+        let mut tx = self.pool.begin().await?;
+        self.set_phc(&mut tx, user, &p, new).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Update the password of `user` to `new` only if its hash is still `expected_phc`,
+    /// such as read by [`SafeBox::export`], without checking any password.
+    /// Return `false` if the hash was changed in the meantime,
+    /// and fail with [`Error::UserNotExist`] if `user` does not exist.
+    ///
+    /// This lets several administrators edit passwords without overwriting each other's changes.
+    pub async fn update_if(
+        &self,
+        user: &str,
+        expected_phc: &str,
+        new: &str,
+    ) -> Result<bool, Error> {
+        self.writable()?;
+        let user = &*self.canon(user);
+        self.validate(new.as_bytes())?;
+        let p = self.hash(new.as_bytes()).await?;
+        let mut tx = self.pool.begin().await?;
+        // lock the row, and take the write lock up front on SQLite
+        let q = format!(
+            r#"UPDATE "{}" SET phc = phc WHERE "user" = $1 AND phc = $2"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user).bind(expected_phc);
+        if q.execute(&mut *tx).await?.rows_affected() == 0 {
+            let q = format!(r#"SELECT 1 FROM "{}" WHERE "user" = $1"#, self.tables.main);
+            let q = query(&q).bind(user);
+            if q.fetch_optional(&mut *tx).await?.is_none() {
+                return Err(Error::UserNotExist(user.to_owned()));
+            }
+            return Ok(false);
+        }
+        self.set_phc(&mut tx, user, &p, new.as_bytes()).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Change the password of `user` from `old` to `new`, for users changing their own password.
+    /// Fail with [`Error::WrongPassword`] if `old` is wrong.
+    ///
+    /// Unlike [`SafeBox::update`], all tokens of `user` are invalidated,
+    /// so that a stolen session does not outlive the change.
+    pub async fn change_password(&self, user: &str, old: &str, new: &str) -> Result<(), Error> {
+        let user = &*self.canon(user);
+        if self
+            .check(&self.pool, user, old.as_bytes())
+            .await?
+            .is_none()
+        {
+            return Err(Error::WrongPassword(user.to_owned()));
+        }
+        self.validate(new.as_bytes())?;
+        let p = self.hash(new.as_bytes()).await?;
+        let mut tx = self.pool.begin().await?;
+        self.set_phc(&mut tx, user, &p, new.as_bytes()).await?;
+        tx.commit().await?;
+        self.invalidate_user_token(user).await
+    }
+
+    /// Replace the password hash of `user`, which is already canonical, with `phc`,
+    /// the hash of `new`, checking the password history.
+    /// Fail with [`Error::UserNotExist`] if no row matched.
+    async fn set_phc(
+        &self,
+        conn: &mut Connection,
+        user: &str,
+        phc: &str,
+        new: &[u8],
+    ) -> Result<(), Error> {
+        self.writable()?;
+        if self.history > 0 {
+            // write first, so that the transaction takes the write lock up front;
+            // order by `seq`, as changes within the same millisecond share `changed_at`
+            let q = format!(
+                r#"INSERT INTO "{0}" ("user", phc, changed_at, seq) SELECT "user", phc, $1, (SELECT COALESCE(MAX(seq), 0) + 1 FROM "{0}" WHERE "user" = $2) FROM "{1}" WHERE "user" = $2"#,
+                self.tables.history, self.tables.main
+            );
+            let q = query(&q).bind(unix_ms(self.clock.now())).bind(user);
+            q.execute(&mut *conn).await?;
+            let q = format!(
+                r#"SELECT phc FROM "{}" WHERE "user" = $1 ORDER BY COALESCE(seq, 0) DESC, changed_at DESC"#,
+                self.tables.history
+            );
+            let q = query(&q).bind(user);
+            let v = q.fetch_all(&mut *conn).await?;
+            let prev = v
+                .iter()
+                .map(|row| row.try_get("phc"))
+                .collect::<Result<Vec<&str>, _>>()?;
+            let (keep, forget) = prev.split_at(prev.len().min(self.history));
+            for phc in keep {
+                if self.verify_hash(phc, new).await? {
+                    return Err(Error::PasswordReused(user.to_owned()));
+                }
+            }
+            for phc in forget {
+                let q = format!(
+                    r#"DELETE FROM "{}" WHERE "user" = $1 AND phc = $2"#,
+                    self.tables.history
+                );
+                let q = query(&q).bind(user).bind(phc);
+                q.execute(&mut *conn).await?;
+            }
+        }
+        let q = format!(
+            r#"UPDATE "{}" SET phc = $1, hash_algo = $2, must_change = FALSE WHERE "user" = $3"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(phc).bind(phc_algorithm(phc)).bind(user);
+        if q.execute(&mut *conn).await?.rows_affected() == 0 {
+            return Err(Error::UserNotExist(user.to_owned()));
+        }
         Ok(())
     }
 
-    /// Delate a user entry.
+    /// Check whether `user` exists, without hashing anything.
+    pub async fn exists(&self, user: &str) -> Result<bool, Error> {
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"SELECT 1 FROM "{}" WHERE "user" = $1 LIMIT 1"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user);
+        let v = q.fetch_optional(&self.pool).await?;
+        Ok(v.is_some())
+    }
+
+    /// Count the users in the database.
+    /// This also refreshes the count of [`SafeBoxBuilder::cache_user_cnt`].
+    pub async fn user_cnt(&self) -> Result<usize, Error> {
+        let n = db::count(&self.pool, &self.tables.main).await?;
+        if let Some(cnt) = &self.user_cnt {
+            cnt.store(n, Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+
+    /// Get the number of users without querying the database,
+    /// or `None` without [`SafeBoxBuilder::cache_user_cnt`].
+    ///
+    /// The count follows the users created and deleted through this `SafeBox`,
+    /// once their transaction is committed.
+    /// Changes made by other processes are only seen after [`SafeBox::user_cnt`] is called,
+    /// and a change committed concurrently with that call may be counted twice or missed.
+    pub fn user_cnt_cached(&self) -> Option<usize> {
+        self.user_cnt
+            .as_ref()
+            .map(|cnt| cnt.load(Ordering::Relaxed))
+    }
+
+    /// Account for users `created` and `deleted` in the cached count, if any.
+    fn count_users(&self, created: usize, deleted: usize) {
+        if let Some(cnt) = &self.user_cnt {
+            let f = |n: usize| Some((n + created).saturating_sub(deleted));
+            let _ = cnt.fetch_update(Ordering::Relaxed, Ordering::Relaxed, f);
+        }
+    }
+
+    /// Get the parameters the password of `user` was hashed with,
+    /// or `None` if the user does not exist.
+    /// Neither the hash nor the salt are revealed.
+    pub async fn hash_params(&self, user: &str) -> Result<Option<HashParams>, Error> {
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"SELECT phc FROM "{}" WHERE "user" = $1"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user);
+        let Some(row) = q.fetch_optional(&self.pool).await? else {
+            return Ok(None);
+        };
+        HashParams::parse(row.try_get("phc")?).map(Some)
+    }
+
+    /// Count the users whose password is hashed with the algorithm set by
+    /// [`SafeBox::with_migration_target`], returning `(migrated, total)`.
+    /// Fail with [`Error::InvalidConfig`] if no target is set.
+    pub async fn migration_progress(&self) -> Result<(usize, usize), Error> {
+        let Some(target) = &self.migration_target else {
+            return Err(Error::InvalidConfig("no migration target".to_owned()));
+        };
+        let q = format!(
+            r#"SELECT COUNT(CASE WHEN hash_algo = $1 THEN 1 END), COUNT(*) FROM "{}""#,
+            self.tables.main
+        );
+        let q = query_as(&q).bind(target);
+        let (migrated, total): (i64, i64) = q.fetch_one(&self.pool).await?;
+        Ok((migrated as usize, total as usize))
+    }
+
+    /// List the users whose password was hashed differently than new passwords are,
+    /// for example before the Argon2 parameters were raised or another algorithm was chosen.
+    /// Their passwords are hashed again anyway the next time they log in,
+    /// see [`SafeBox::authenticate`].
+    ///
+    /// Hashes are read in pages of 1000 like [`SafeBox::stream_users`],
+    /// so that only the names of the listed users are kept in memory.
+    pub async fn needs_rehash(&self) -> Result<Vec<String>, Error> {
+        let q = format!(
+            r#"SELECT "user", phc FROM "{}" WHERE $1 IS NULL OR "user" > $1 ORDER BY "user" LIMIT $2"#,
+            self.tables.main
+        );
+        let mut users = vec![];
+        let mut after: Option<String> = None;
+        loop {
+            let page = query(&q).bind(&after).bind(STREAM_PAGE);
+            let v = page.fetch_all(&self.pool).await?;
+            for row in &v {
+                if self.hasher().outdated(row.try_get("phc")?) {
+                    users.push(row.try_get("user")?);
+                }
+            }
+            match v.last() {
+                Some(row) if v.len() as i64 == STREAM_PAGE => after = Some(row.try_get("user")?),
+                _ => break,
+            }
+        }
+        Ok(users)
+    }
+
+    /// Disable `user`, so that it can neither log in nor be issued tokens, and invalidate its tokens.
+    /// Signed tokens stay valid until they expire.
+    pub async fn disable(&self, user: &str) -> Result<(), Error> {
+        self.set_disabled(user, true).await?;
+        self.invalidate_user_token(user).await
+    }
+
+    /// Enable `user` again after [`SafeBox::disable`].
+    pub async fn enable(&self, user: &str) -> Result<(), Error> {
+        self.set_disabled(user, false).await
+    }
+
+    /// Set whether `user` must change its password, as after [`SafeBox::admin_reset`],
+    /// which [`SafeBox::verify_detailed`] and the tokens of [`SafeBox::verify`] report.
+    /// The flag is cleared when the password is changed, such as with [`SafeBox::change_password`].
+    pub async fn set_must_change(&self, user: &str, must_change: bool) -> Result<(), Error> {
+        self.writable()?;
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"UPDATE "{}" SET must_change = $1 WHERE "user" = $2"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(must_change).bind(user);
+        let res = q.execute(&self.pool).await?;
+        if res.rows_affected() == 0 {
+            return Err(Error::UserNotExist(user.to_owned()));
+        }
+        Ok(())
+    }
+
+    async fn set_disabled(&self, user: &str, disabled: bool) -> Result<(), Error> {
+        self.writable()?;
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"UPDATE "{}" SET disabled = $1 WHERE "user" = $2"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(disabled).bind(user);
+        let res = q.execute(&self.pool).await?;
+        if res.rows_affected() == 0 {
+            return Err(Error::UserNotExist(user.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Get the time `user` last logged in with [`SafeBox::verify`].
+    /// Return `None` if the user has never logged in.
+    pub async fn last_login(&self, user: &str) -> Result<Option<SystemTime>, Error> {
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"SELECT last_login FROM "{}" WHERE "user" = $1"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user);
+        let row = q.fetch_optional(&self.pool).await?;
+        let row = row.ok_or_else(|| Error::UserNotExist(user.to_owned()))?;
+        let t: Option<i64> = row.try_get("last_login")?;
+        Ok(t.map(from_unix_ms))
+    }
+
+    /// List at most `limit` users, skipping the first `offset`, sorted by username.
+    pub async fn list_users(&self, offset: usize, limit: usize) -> Result<Vec<String>, Error> {
+        let q = format!(
+            r#"SELECT "user" FROM "{}" ORDER BY "user" LIMIT $1 OFFSET $2"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(limit as i64).bind(offset as i64);
+        let v = q.fetch_all(&self.pool).await?;
+        v.iter().map(|row| Ok(row.try_get("user")?)).collect()
+    }
+
+    /// Stream all users, sorted by username, with bounded memory.
+    ///
+    /// Users are fetched in pages of 1000 by separate queries,
+    /// so that no connection is held between pages and writes can go on meanwhile.
+    /// Users created or deleted while streaming may thus be missed or included.
+    pub fn stream_users(&self) -> impl Stream<Item = Result<String, Error>> + '_ {
+        let pages = stream::try_unfold(
+            Some(None),
+            move |after: Option<Option<String>>| async move {
+                let Some(after) = after else {
+                    return Ok(None);
+                };
+                let q = format!(
+                    r#"SELECT "user" FROM "{}" WHERE $1 IS NULL OR "user" > $1 ORDER BY "user" LIMIT $2"#,
+                    self.tables.main
+                );
+                let q = query(&q).bind(after).bind(STREAM_PAGE);
+                let v = q.fetch_all(&self.pool).await?;
+                let page = v
+                    .iter()
+                    .map(|row| row.try_get("user"))
+                    .collect::<Result<Vec<String>, _>>()?;
+                if page.is_empty() {
+                    return Ok(None);
+                }
+                let next = (page.len() as i64 == STREAM_PAGE).then(|| page.last().cloned());
+                Ok::<_, Error>(Some((stream::iter(page.into_iter().map(Ok)), next)))
+            },
+        );
+        pages.try_flatten()
+    }
+
+    /// Rename user `old` to `new`, keeping the password.
+    /// Tokens issued to `old` are invalidated.
+    pub async fn rename(&self, old: &str, new: &str) -> Result<(), Error> {
+        self.writable()?;
+        let old = &*self.canon(old);
+        let new = &*self.canon(new);
+        self.validate_user(new)?;
+        {
+            let mut tx = self.pool.begin().await?;
+            let q = format!(
+                r#"UPDATE "{}" SET "user" = $1 WHERE "user" = $2"#,
+                self.tables.main
+            );
+            let q = query(&q).bind(new).bind(old);
+            match q.execute(&mut *tx).await {
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                    return Err(Error::UserAlreadyExist(new.to_owned()))
+                }
+                Ok(v) if v.rows_affected() == 0 => return Err(Error::UserNotExist(old.to_owned())),
+                res => res?,
+            };
+            let q = format!(
+                r#"UPDATE "{}" SET "user" = $1 WHERE "user" = $2"#,
+                self.tables.history
+            );
+            let q = query(&q).bind(new).bind(old);
+            q.execute(&mut *tx).await?;
+            let q = format!(
+                r#"UPDATE "{}" SET "user" = $1 WHERE "user" = $2"#,
+                self.tables.meta
+            );
+            let q = query(&q).bind(new).bind(old);
+            q.execute(&mut *tx).await?;
+            tx.commit().await?;
+        }
+        self.invalidate_user_token(old).await
+    }
+
+    /// Delate a user entry and invalidate its tokens.
+    /// Fail with [`Error::UserNotExist`] if `user` does not exist,
+    /// even if it is removed concurrently after its password was checked.
+    /// # Example
+    /// ```
+    /// # #[cfg(not(feature = "postgres"))]
+    /// # tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+    /// let safe = safe_box::SafeBox::new_in_memory().await?;
+    /// safe.create("alice", "password").await?;
+    /// let token = safe.verify("alice", "password").await?.unwrap();
+    /// safe.delete("alice", "password").await?;
+    /// assert!(safe.verify_token(&token).is_err());
+    /// # Ok::<_, safe_box::Error>(())
+    /// # }).unwrap();
+    /// ```
     pub async fn delete(&self, user: &str, pass: &str) -> Result<(), Error> {
-        self.verify(user, pass).await?;
-        let q = query("DELETE FROM main WHERE user = ?").bind(user);
-        q.execute(self.conn.lock().await.deref_mut()).await?;
+        let user = &*self.canon(user);
+        self.check_pass(&self.pool, user, pass.as_bytes()).await?;
+        let mut tx = self.pool.begin().await?;
+        self.remove(&mut tx, user).await?;
+        tx.commit().await?;
+        self.count_users(0, 1);
+        self.emit(Event::Deleted { user });
+        self.invalidate_user_token(user).await
+    }
+
+    /// Delete a user entry without checking its password, for example on behalf of an administrator,
+    /// and invalidate its tokens.
+    /// Return whether `user` existed, so that deleting it again is not an error,
+    /// unlike with [`SafeBox::delete`].
+    pub async fn delete_if_exists(&self, user: &str) -> Result<bool, Error> {
+        let user = &*self.canon(user);
+        let mut tx = self.pool.begin().await?;
+        match self.remove(&mut tx, user).await {
+            Err(Error::UserNotExist(_)) => return Ok(false),
+            res => res?,
+        }
+        tx.commit().await?;
+        self.count_users(0, 1);
+        self.emit(Event::Deleted { user });
+        self.invalidate_user_token(user).await?;
+        Ok(true)
+    }
+
+    /// Delete the users that have not logged in for `older_than`,
+    /// or were created longer ago and never logged in, and invalidate their tokens.
+    /// Return the number of deleted users.
+    /// Users that existed before creation times were recorded count as created
+    /// when the database was upgraded.
+    ///
+    /// Fail with [`Error::InvalidConfig`] if `older_than` is zero, which would delete everyone.
+    pub async fn purge_inactive(&self, older_than: Duration) -> Result<usize, Error> {
+        self.writable()?;
+        if older_than.is_zero() {
+            return Err(Error::InvalidConfig(
+                "cannot purge users inactive for no time".to_owned(),
+            ));
+        }
+        let Some(cutoff) = self.clock.now().checked_sub(older_than) else {
+            return Ok(0);
+        };
+        let mut tx = self.pool.begin().await?;
+        let q = format!(
+            r#"DELETE FROM "{}" WHERE COALESCE(last_login, created_at) < $1 RETURNING "user""#,
+            self.tables.main
+        );
+        let q = query_scalar(&q).bind(unix_ms(cutoff));
+        let users: Vec<String> = q.fetch_all(&mut *tx).await?;
+        for user in &users {
+            self.remove_data(&mut tx, user).await?;
+        }
+        tx.commit().await?;
+        metric::count(metric::USER_DELETED, users.len() as u64);
+        self.count_users(0, users.len());
+        for user in &users {
+            self.emit(Event::Deleted { user });
+            self.invalidate_user_token(user).await?;
+        }
+        trace!("purged {} inactive users", users.len());
+        Ok(users.len())
+    }
+
+    /// Check that the database can be reached, for liveness and readiness probes.
+    /// This acquires a connection from the pool and runs a trivial query on it.
+    pub async fn ping(&self) -> Result<(), Error> {
+        query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Get the number of tokens and of connections to the database.
+    /// With the `metrics` feature, these are also recorded as gauges, see [`metric`],
+    /// so calling this periodically feeds dashboards.
+    pub fn stats(&self) -> SafeStats {
+        let stats = SafeStats {
+            tokens: self.token_count(),
+            connections: self.pool.size(),
+            idle_connections: self.pool.num_idle(),
+        };
+        metric::stats(&stats);
+        stats
+    }
+
+    /// Close the connections to the database, waiting for those in use to be returned.
+    /// Persistent tokens are already stored when issued, so nothing is lost.
+    ///
+    /// Dropping a `SafeBox` also closes the connections, but without waiting,
+    /// so the database may still be open when reopening it right after.
+    pub async fn close(self) -> Result<(), Error> {
+        self.pool.close().await;
+        Ok(())
+    }
+
+    /// Delete the rows of `user`, which is already canonical.
+    /// Fail with [`Error::UserNotExist`] if no row matched.
+    async fn remove(&self, conn: &mut Connection, user: &str) -> Result<(), Error> {
+        self.writable()?;
+        let q = format!(r#"DELETE FROM "{}" WHERE "user" = $1"#, self.tables.main);
+        let q = query(&q).bind(user);
+        if q.execute(&mut *conn).await?.rows_affected() == 0 {
+            return Err(Error::UserNotExist(user.to_owned()));
+        }
+        metric::count(metric::USER_DELETED, 1);
+        self.remove_data(conn, user).await
+    }
+
+    /// Delete the rows about `user` outside of the table of users.
+    async fn remove_data(&self, conn: &mut Connection, user: &str) -> Result<(), Error> {
+        let q = format!(r#"DELETE FROM "{}" WHERE "user" = $1"#, self.tables.history);
+        let q = query(&q).bind(user);
+        q.execute(&mut *conn).await?;
+        let q = format!(r#"DELETE FROM "{}" WHERE "user" = $1"#, self.tables.meta);
+        let q = query(&q).bind(user);
+        q.execute(&mut *conn).await?;
+        if self.persist() {
+            // so that the tokens are not loaded again should invalidating them fail
+            let q = format!(r#"DELETE FROM "{}" WHERE "user" = $1"#, self.tables.token);
+            let q = query(&q).bind(user);
+            q.execute(&mut *conn).await?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,78 @@
+//! Attributes of users, such as their email or display name, stored as key-value pairs.
+
+use std::collections::HashMap;
+
+use sqlx::{query, Row};
+
+use crate::{Error, SafeBox};
+
+impl SafeBox {
+    /// Set the attribute `key` of `user` to `value`, replacing any previous value.
+    /// Fail with [`Error::UserNotExist`] if `user` does not exist.
+    ///
+    /// Attributes are removed along with their user.
+    pub async fn set_meta(&self, user: &str, key: &str, value: &str) -> Result<(), Error> {
+        self.writable()?;
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"INSERT INTO "{}" ("user", "key", value) SELECT "user", $2, $3 FROM "{}" WHERE "user" = $1 ON CONFLICT ("user", "key") DO UPDATE SET value = excluded.value"#,
+            self.tables.meta, self.tables.main
+        );
+        let q = query(&q).bind(user).bind(key).bind(value);
+        if q.execute(&self.pool).await?.rows_affected() == 0 {
+            return Err(Error::UserNotExist(user.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Get the attribute `key` of `user`, or `None` if it is not set.
+    pub async fn get_meta(&self, user: &str, key: &str) -> Result<Option<String>, Error> {
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"SELECT value FROM "{}" WHERE "user" = $1 AND "key" = $2"#,
+            self.tables.meta
+        );
+        let q = query(&q).bind(user).bind(key);
+        let row = q.fetch_optional(&self.pool).await?;
+        Ok(row.map(|row| row.try_get("value")).transpose()?)
+    }
+
+    /// Get all attributes of `user`, which are none if the user does not exist.
+    pub async fn all_meta(&self, user: &str) -> Result<HashMap<String, String>, Error> {
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"SELECT "key", value FROM "{}" WHERE "user" = $1"#,
+            self.tables.meta
+        );
+        let q = query(&q).bind(user);
+        let v = q.fetch_all(&self.pool).await?;
+        v.iter()
+            .map(|row| Ok((row.try_get("key")?, row.try_get("value")?)))
+            .collect()
+    }
+
+    /// Find the users whose attribute `key` is `value`, sorted by username,
+    /// such as those with the role `admin`.
+    pub async fn find_by_meta(&self, key: &str, value: &str) -> Result<Vec<String>, Error> {
+        let q = format!(
+            r#"SELECT m."user" FROM "{}" m JOIN "{}" u ON u."user" = m."user" WHERE m."key" = $1 AND m.value = $2 ORDER BY m."user""#,
+            self.tables.meta, self.tables.main
+        );
+        let q = query(&q).bind(key).bind(value);
+        let v = q.fetch_all(&self.pool).await?;
+        v.iter().map(|row| Ok(row.try_get("user")?)).collect()
+    }
+
+    /// Remove the attribute `key` of `user`, if set.
+    pub async fn remove_meta(&self, user: &str, key: &str) -> Result<(), Error> {
+        self.writable()?;
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"DELETE FROM "{}" WHERE "user" = $1 AND "key" = $2"#,
+            self.tables.meta
+        );
+        let q = query(&q).bind(user).bind(key);
+        q.execute(&self.pool).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Names of the counters and gauges recorded with the `metrics` feature.
+//!
+//! Metrics are recorded through the [`metrics`](https://docs.rs/metrics) crate,
+//! so they reach whichever recorder the application installs.
+
+use crate::{SafeStats, VerifyOutcome};
+
+/// Password checks, labeled with their `outcome`:
+/// `authenticated`, `wrong_password`, `user_not_found`, `locked` or `disabled`.
+pub const VERIFY: &str = "safe_box_verify_total";
+
+/// Users created.
+pub const USER_CREATED: &str = "safe_box_users_created_total";
+
+/// Users deleted.
+pub const USER_DELETED: &str = "safe_box_users_deleted_total";
+
+/// Tokens issued.
+pub const TOKEN_ISSUED: &str = "safe_box_tokens_issued_total";
+
+/// Tokens invalidated before they expired.
+pub const TOKEN_INVALIDATED: &str = "safe_box_tokens_invalidated_total";
+
+/// Tokens held in memory, as of the last call to [`SafeBox::stats`](crate::SafeBox::stats).
+pub const TOKENS: &str = "safe_box_tokens";
+
+/// Open connections to the database, as of the last call to [`SafeBox::stats`](crate::SafeBox::stats).
+pub const CONNECTIONS: &str = "safe_box_connections";
+
+/// Idle connections to the database, as of the last call to [`SafeBox::stats`](crate::SafeBox::stats).
+pub const IDLE_CONNECTIONS: &str = "safe_box_idle_connections";
+
+/// Increment the counter `name` by `n`.
+#[cfg(feature = "metrics")]
+pub(crate) fn count(name: &'static str, n: u64) {
+    metrics::counter!(name).increment(n);
+}
+
+/// Does nothing without the `metrics` feature.
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn count(_name: &'static str, _n: u64) {}
+
+/// Count a password check with `outcome`.
+#[cfg(feature = "metrics")]
+pub(crate) fn verify(outcome: VerifyOutcome) {
+    let outcome = match outcome {
+        VerifyOutcome::Authenticated => "authenticated",
+        VerifyOutcome::WrongPassword => "wrong_password",
+        VerifyOutcome::UserNotFound => "user_not_found",
+        VerifyOutcome::Locked => "locked",
+        VerifyOutcome::Disabled => "disabled",
+    };
+    metrics::counter!(VERIFY, "outcome" => outcome).increment(1);
+}
+
+/// Does nothing without the `metrics` feature.
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn verify(_outcome: VerifyOutcome) {}
+
+/// Set the gauges to `stats`.
+#[cfg(feature = "metrics")]
+pub(crate) fn stats(stats: &SafeStats) {
+    metrics::gauge!(TOKENS).set(stats.tokens as f64);
+    metrics::gauge!(CONNECTIONS).set(stats.connections);
+    metrics::gauge!(IDLE_CONNECTIONS).set(stats.idle_connections as f64);
+}
+
+/// Does nothing without the `metrics` feature.
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn stats(_stats: &SafeStats) {}
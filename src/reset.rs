@@ -0,0 +1,144 @@
+//! Single-use tokens for resetting forgotten passwords.
+
+use std::time::Duration;
+
+use sqlx::{query, Row};
+use tracing::trace;
+
+use crate::{redact, token::random_token, token_id, token_key, Error, SafeBox, TokenEntry};
+
+/// Default lifetime of password reset tokens.
+pub(crate) const RESET_TTL: Duration = Duration::from_secs(900);
+
+/// Number of random bytes of temporary passwords, giving 16 base64 characters.
+const TEMP_PASS_LEN: usize = 12;
+
+impl SafeBox {
+    /// Issue a token that allows to reset the password of `user` once with [`SafeBox::reset_password`],
+    /// and expires after 15 minutes unless set otherwise with
+    /// [`SafeBoxBuilder::reset_token_ttl`](crate::SafeBoxBuilder::reset_token_ttl).
+    /// Fail with [`Error::UserNotExist`] if `user` does not exist,
+    /// and [`Error::AccountDisabled`] if the user is disabled.
+    ///
+    /// Reset tokens cannot be used to log in, and are only kept in memory.
+    /// To not reveal which users exist, the caller should answer the same whether this succeeds or not.
+    pub async fn issue_reset_token(&self, user: &str) -> Result<String, Error> {
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"SELECT phc, disabled FROM "{}" WHERE "user" = $1"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user);
+        let phc: String = match q.fetch_optional(&self.pool).await? {
+            None => return Err(Error::UserNotExist(user.to_owned())),
+            Some(row) if row.try_get("disabled")? => {
+                return Err(Error::AccountDisabled(user.to_owned()))
+            }
+            Some(row) => row.try_get("phc")?,
+        };
+        let token = self.token_gen.generate();
+        let issued = self.clock.now();
+        let expire = issued
+            .checked_add(self.reset_ttl)
+            .ok_or_else(|| Error::InvalidConfig("reset token lifetime is too long".to_owned()))?;
+        let entry = TokenEntry {
+            id: token_id(&token),
+            user: user.to_owned(),
+            issued,
+            expire,
+            scopes: vec![],
+        };
+        self.reset
+            .lock()
+            .unwrap()
+            .insert(token_key(&token), (entry, phc));
+        trace!("issued reset token {} to '{user}'", redact(&token));
+        Ok(token)
+    }
+
+    /// Set the password of the user `reset_token` was issued to to `new`,
+    /// and invalidate all tokens of that user.
+    /// Fail with [`Error::BadToken`] if `reset_token` is unknown, expired or already used,
+    /// or if the password of the user changed since the token was issued.
+    ///
+    /// The token is consumed once the password is changed.
+    /// If `new` is refused, such as by the password policy, the token can be used again.
+    pub async fn reset_password(&self, reset_token: &str, new: &str) -> Result<(), Error> {
+        self.validate(new.as_bytes())?;
+        let key = token_key(reset_token);
+        let entry = match self.reset.lock().unwrap().remove(&key) {
+            Some(e) if self.clock.now() < e.0.expire => e,
+            _ => return Err(Error::BadToken(reset_token.to_owned())),
+        };
+        let (user, old) = (entry.0.user.clone(), entry.1.clone());
+        let res = async {
+            let p = self.hash(new.as_bytes()).await?;
+            let mut tx = self.pool.begin().await?;
+            // lock the row, and take the write lock up front on SQLite
+            let q = format!(
+                r#"UPDATE "{}" SET phc = phc WHERE "user" = $1 AND phc = $2"#,
+                self.tables.main
+            );
+            let q = query(&q).bind(&user).bind(&old);
+            if q.execute(&mut *tx).await?.rows_affected() == 0 {
+                // the password was changed, or the user replaced, since the token was issued
+                return Err(Error::BadToken(reset_token.to_owned()));
+            }
+            self.set_phc(&mut tx, &user, &p, new.as_bytes()).await?;
+            tx.commit().await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            if !matches!(e, Error::UserNotExist(_) | Error::BadToken(_)) {
+                self.reset.lock().unwrap().insert(key, entry);
+            }
+            return Err(e);
+        }
+        trace!(
+            "reset password of '{user}' with token {}",
+            redact(reset_token)
+        );
+        self.invalidate_user_token(&user).await
+    }
+
+    /// Set the password of `user` to a random temporary one, for example on behalf of a help desk,
+    /// invalidate all tokens of that user, and return the password to relay to them.
+    /// Fail with [`Error::UserNotExist`] if `user` does not exist.
+    ///
+    /// The password is only returned here, so it must be handed over right away.
+    /// It is not checked against the password policy,
+    /// and the user must change it, see [`SafeBox::set_must_change`].
+    pub async fn admin_reset(&self, user: &str) -> Result<String, Error> {
+        self.writable()?;
+        let user = &*self.canon(user);
+        let pass = random_token(None, TEMP_PASS_LEN);
+        let p = self.hash(pass.as_bytes()).await?;
+        let mut tx = self.pool.begin().await?;
+        self.set_phc(&mut tx, user, &p, pass.as_bytes()).await?;
+        let q = format!(
+            r#"UPDATE "{}" SET must_change = TRUE WHERE "user" = $1"#,
+            self.tables.main
+        );
+        query(&q).bind(user).execute(&mut *tx).await?;
+        tx.commit().await?;
+        trace!("reset password of '{user}' to a temporary one");
+        self.invalidate_user_token(user).await?;
+        Ok(pass)
+    }
+
+    /// Number of reset tokens held in memory, including expired ones not removed yet
+    /// by [`SafeBox::expire_reset_token`].
+    pub fn reset_token_count(&self) -> usize {
+        self.reset.lock().unwrap().len()
+    }
+
+    /// Remove all expired reset tokens.
+    pub fn expire_reset_token(&self) {
+        let now = self.clock.now();
+        self.reset
+            .lock()
+            .unwrap()
+            .retain(|_, (e, _)| now < e.expire);
+    }
+}
@@ -0,0 +1,114 @@
+//! Versioning of the tables of a [`SafeBox`](crate::SafeBox).
+
+use std::time::SystemTime;
+
+use sqlx::{query, query_scalar, Row};
+
+use crate::{
+    db::{self, Pool},
+    hasher::phc_algorithm,
+    unix_ms, Error, Tables, HISTORY_COLUMNS, MAIN_COLUMNS,
+};
+
+/// Version of the schema of the tables created by this version of the crate.
+///
+/// The version of a database is stored in its `schema_version` table,
+/// or `<name>_schema_version` for a store named with [`SafeBoxBuilder::table`](crate::SafeBoxBuilder::table),
+/// and upgraded when the database is opened for writing.
+/// Databases created before versioning are at version 0, and upgraded like new ones.
+///
+/// Version 1 is the first versioned schema: the table of users,
+/// with the `last_login`, `disabled` and `totp_secret` columns,
+/// and the tables of password history and of user attributes.
+/// Version 2 indexes attributes by key and value, see [`SafeBox::find_by_meta`](crate::SafeBox::find_by_meta).
+/// Version 3 records the algorithm of each password hash in the `hash_algo` column,
+/// see [`SafeBox::migration_progress`](crate::SafeBox::migration_progress).
+/// Version 4 records when users are created in the `created_at` column,
+/// see [`SafeBox::purge_inactive`](crate::SafeBox::purge_inactive).
+/// Version 5 marks temporary passwords in the `must_change` column,
+/// see [`SafeBox::admin_reset`](crate::SafeBox::admin_reset).
+/// Version 6 orders previous password hashes by the `seq` column,
+/// as several changes may share the same `changed_at`.
+pub const SCHEMA_VERSION: i64 = 6;
+
+/// Bring the tables to [`SCHEMA_VERSION`], applying the pending migrations in order.
+/// Fail with [`Error::InvalidData`] if the database is already at a later version,
+/// as this version of the crate may then misread it.
+///
+/// Migrations are idempotent, so that a migration interrupted before its version is recorded
+/// is simply run again.
+pub(crate) async fn upgrade(pool: &Pool, tables: &Tables) -> Result<(), Error> {
+    query(&tables.init_schema()).execute(pool).await?;
+    let q = format!(r#"SELECT MAX(version) FROM "{}""#, tables.schema);
+    let version: Option<i64> = query_scalar(&q).fetch_one(pool).await?;
+    let version = version.unwrap_or(0);
+    if version > SCHEMA_VERSION {
+        return Err(Error::InvalidData(format!(
+            "schema version {version} is newer than the supported {SCHEMA_VERSION}"
+        )));
+    }
+    for v in version + 1..=SCHEMA_VERSION {
+        migrate(pool, tables, v).await?;
+        let q = format!(r#"INSERT INTO "{}" (version) VALUES ($1)"#, tables.schema);
+        query(&q).bind(v).execute(pool).await?;
+    }
+    Ok(())
+}
+
+/// Apply the migration to `version`.
+/// New versions add an arm here and bump [`SCHEMA_VERSION`].
+async fn migrate(pool: &Pool, tables: &Tables, version: i64) -> Result<(), Error> {
+    match version {
+        1 => {
+            query(&tables.init()).execute(pool).await?;
+            db::migrate(pool, &tables.main, MAIN_COLUMNS).await?;
+            query(&tables.init_history()).execute(pool).await?;
+            query(&tables.init_meta()).execute(pool).await?;
+        }
+        2 => {
+            let q = format!(
+                r#"CREATE INDEX IF NOT EXISTS "{0}_key_value" ON "{0}" ("key", value)"#,
+                tables.meta
+            );
+            query(&q).execute(pool).await?;
+        }
+        3 => {
+            db::migrate(pool, &tables.main, MAIN_COLUMNS).await?;
+            let q = format!(
+                r#"SELECT "user", phc FROM "{}" WHERE hash_algo IS NULL"#,
+                tables.main
+            );
+            let v = query(&q).fetch_all(pool).await?;
+            let q = format!(
+                r#"UPDATE "{}" SET hash_algo = $1 WHERE "user" = $2"#,
+                tables.main
+            );
+            let mut tx = pool.begin().await?;
+            for row in &v {
+                let (user, phc): (&str, &str) = (row.try_get("user")?, row.try_get("phc")?);
+                query(&q)
+                    .bind(phc_algorithm(phc))
+                    .bind(user)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        }
+        4 => {
+            db::migrate(pool, &tables.main, MAIN_COLUMNS).await?;
+            let q = format!(
+                r#"UPDATE "{}" SET created_at = $1 WHERE created_at IS NULL"#,
+                tables.main
+            );
+            query(&q)
+                .bind(unix_ms(SystemTime::now()))
+                .execute(pool)
+                .await?;
+        }
+        5 => db::migrate(pool, &tables.main, MAIN_COLUMNS).await?,
+        // rows from before have no `seq`, and keep being ordered by `changed_at`
+        6 => db::migrate(pool, &tables.history, HISTORY_COLUMNS).await?,
+        _ => unreachable!("no migration to schema version {version}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! Stateless tokens signed with HMAC-SHA256.
+//!
+//! A signed token has the form `<payload>.<signature>`, both base64-encoded,
+//! where the payload is `<issued>:<expire>:<user>` with timestamps in UNIX milliseconds.
+//! The signature is the HMAC of the payload.
+//!
+//! A token with scopes has the form `<payload>.<scopes>.<signature>`,
+//! where the scopes are separated by spaces and the signature is the HMAC of
+//! `<payload>.<scopes>` in base64. This never equals a payload, which contains `:`.
+
+use std::time::SystemTime;
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{from_unix_ms, join_scopes, split_scopes, unix_ms};
+
+pub(crate) type Key = Hmac<Sha256>;
+
+/// Claims carried by a signed token.
+pub(crate) struct Claims {
+    pub user: String,
+    pub issued: SystemTime,
+    pub expire: SystemTime,
+    pub scopes: Vec<String>,
+}
+
+pub(crate) fn key(secret: &[u8]) -> Key {
+    Key::new_from_slice(secret).expect("HMAC accepts keys of any length")
+}
+
+pub(crate) fn encode(key: &Key, claims: &Claims) -> String {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let payload = format!(
+        "{}:{}:{}",
+        unix_ms(claims.issued),
+        unix_ms(claims.expire),
+        claims.user
+    );
+    let mut mac = key.clone();
+    if claims.scopes.is_empty() {
+        mac.update(payload.as_bytes());
+        let sig = mac.finalize().into_bytes();
+        return format!("{}.{}", engine.encode(payload), engine.encode(sig));
+    }
+    let signed = format!(
+        "{}.{}",
+        engine.encode(payload),
+        engine.encode(join_scopes(&claims.scopes))
+    );
+    mac.update(signed.as_bytes());
+    let sig = mac.finalize().into_bytes();
+    format!("{signed}.{}", engine.encode(sig))
+}
+
+/// Decode a signed token, checking its signature but not its expiry.
+pub(crate) fn decode(key: &Key, token: &str) -> Option<Claims> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let (signed, sig) = token.rsplit_once('.')?;
+    let sig = engine.decode(sig).ok()?;
+    let (payload, scopes) = match signed.split_once('.') {
+        Some((payload, scopes)) => (payload, Some(scopes)),
+        None => (signed, None),
+    };
+    let payload = engine.decode(payload).ok()?;
+    let mut mac = key.clone();
+    match scopes {
+        Some(_) => mac.update(signed.as_bytes()),
+        None => mac.update(&payload),
+    }
+    mac.verify_slice(&sig).ok()?;
+    let scopes = match scopes {
+        Some(s) => Some(String::from_utf8(engine.decode(s).ok()?).ok()?),
+        None => None,
+    };
+    let payload = String::from_utf8(payload).ok()?;
+    let mut it = payload.splitn(3, ':');
+    let issued = it.next()?.parse().ok()?;
+    let expire = it.next()?.parse().ok()?;
+    let user = it.next()?.to_owned();
+    Some(Claims {
+        user,
+        issued: from_unix_ms(issued),
+        expire: from_unix_ms(expire),
+        scopes: split_scopes(scopes.as_deref()),
+    })
+}
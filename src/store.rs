@@ -0,0 +1,204 @@
+//! Storage of the tokens issued by a [`SafeBox`](crate::SafeBox).
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+/// Key under which a token is stored, which is its SHA-256 digest.
+/// Stores thus never see the tokens themselves.
+pub type TokenKey = [u8; 32];
+
+/// A token issued to a user, as kept by a [`TokenStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TokenEntry {
+    /// Start of the token that is safe to reveal, see [`redact`](crate::redact).
+    pub id: String,
+    /// User the token belongs to.
+    pub user: String,
+    /// When the token was issued.
+    pub issued: SystemTime,
+    /// When the token expires.
+    pub expire: SystemTime,
+    /// Scopes granted to the token, see [`SafeBox::issue_token_scoped`](crate::SafeBox::issue_token_scoped).
+    pub scopes: Vec<String>,
+}
+
+/// Where a [`SafeBox`](crate::SafeBox) keeps its tokens,
+/// set with [`SafeBoxBuilder::token_store`](crate::SafeBoxBuilder::token_store).
+/// Defaults to a [`MemoryTokenStore`].
+///
+/// Methods are synchronous, as [`SafeBox::verify_token`](crate::SafeBox::verify_token) is,
+/// and are called with no lock held, so that a store may block briefly on its own I/O.
+/// A store shared by several processes lets them accept the tokens issued by one another.
+///
+/// For example, a store backed by Redis could:
+/// - keep each entry, serialized with the `serde` feature, under the base64 of its key,
+///   with `SET key entry PXAT expire` so that Redis drops it when it expires;
+/// - add the key to a set per user, so that [`TokenStore::user_tokens`] and
+///   [`TokenStore::remove_user`] need not scan every key;
+/// - return 0 from [`TokenStore::remove_expired`], leaving expiry to Redis.
+///
+/// Failures cannot be reported, so such a store should treat an unreachable server
+/// as if the token did not exist, which only makes users log in again.
+pub trait TokenStore: Send + Sync {
+    /// Store `entry` under `key`, replacing any previous one.
+    fn insert(&self, key: TokenKey, entry: TokenEntry);
+
+    /// Get the entry under `key`, even if it has expired.
+    fn get(&self, key: &TokenKey) -> Option<TokenEntry>;
+
+    /// Remove the entry under `key`, returning it if there was one.
+    fn remove(&self, key: &TokenKey) -> Option<TokenEntry>;
+
+    /// Set the expiry of the entry under `key` to `expire`, returning whether there was one.
+    /// An entry removed meanwhile must not be stored again.
+    fn set_expire(&self, key: &TokenKey, expire: SystemTime) -> bool;
+
+    /// Remove the entries of `user`, returning how many there were.
+    fn remove_user(&self, user: &str) -> usize;
+
+    /// Remove the entries that expire at `now` or before, returning how many there were.
+    fn remove_expired(&self, now: SystemTime) -> usize;
+
+    /// The entries of `user`, with their keys.
+    fn user_tokens(&self, user: &str) -> Vec<(TokenKey, TokenEntry)>;
+
+    /// Number of entries.
+    fn len(&self) -> usize;
+
+    /// Whether there is no entry.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A store shared by several [`SafeBox`](crate::SafeBox)es in the same process.
+impl<T: TokenStore + ?Sized> TokenStore for Arc<T> {
+    fn insert(&self, key: TokenKey, entry: TokenEntry) {
+        (**self).insert(key, entry)
+    }
+
+    fn get(&self, key: &TokenKey) -> Option<TokenEntry> {
+        (**self).get(key)
+    }
+
+    fn remove(&self, key: &TokenKey) -> Option<TokenEntry> {
+        (**self).remove(key)
+    }
+
+    fn set_expire(&self, key: &TokenKey, expire: SystemTime) -> bool {
+        (**self).set_expire(key, expire)
+    }
+
+    fn remove_user(&self, user: &str) -> usize {
+        (**self).remove_user(user)
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> usize {
+        (**self).remove_expired(now)
+    }
+
+    fn user_tokens(&self, user: &str) -> Vec<(TokenKey, TokenEntry)> {
+        (**self).user_tokens(user)
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+}
+
+/// Number of shards of a [`MemoryTokenStore`], each behind its own lock.
+const SHARDS: usize = 16;
+
+/// The default [`TokenStore`], keeping tokens in memory.
+///
+/// Tokens are spread across shards by the first byte of their key, each behind its own lock,
+/// so that concurrent logins and verifications rarely wait for one another.
+/// As keys are digests, tokens spread evenly.
+pub struct MemoryTokenStore {
+    shards: Vec<RwLock<HashMap<TokenKey, TokenEntry>>>,
+}
+
+impl MemoryTokenStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        let shards = (0..SHARDS).map(|_| RwLock::default()).collect();
+        Self { shards }
+    }
+
+    fn shard(&self, key: &TokenKey) -> &RwLock<HashMap<TokenKey, TokenEntry>> {
+        &self.shards[key[0] as usize % SHARDS]
+    }
+
+    /// Keep only the entries for which `f` is true, sweeping one shard at a time.
+    /// Return the number of removed entries.
+    fn retain(&self, mut f: impl FnMut(&TokenEntry) -> bool) -> usize {
+        let mut removed = 0;
+        for shard in &self.shards {
+            let mut map = shard.write().unwrap();
+            let len = map.len();
+            map.retain(|_, e| f(e));
+            removed += len - map.len();
+        }
+        removed
+    }
+}
+
+impl Default for MemoryTokenStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn insert(&self, key: TokenKey, entry: TokenEntry) {
+        self.shard(&key).write().unwrap().insert(key, entry);
+    }
+
+    fn get(&self, key: &TokenKey) -> Option<TokenEntry> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    fn remove(&self, key: &TokenKey) -> Option<TokenEntry> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    fn set_expire(&self, key: &TokenKey, expire: SystemTime) -> bool {
+        let mut map = self.shard(key).write().unwrap();
+        match map.get_mut(key) {
+            Some(e) => {
+                e.expire = expire;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_user(&self, user: &str) -> usize {
+        self.retain(|e| e.user != user)
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> usize {
+        self.retain(|e| now < e.expire)
+    }
+
+    fn user_tokens(&self, user: &str) -> Vec<(TokenKey, TokenEntry)> {
+        let mut v = vec![];
+        for shard in &self.shards {
+            let map = shard.read().unwrap();
+            v.extend(
+                map.iter()
+                    .filter(|(_, e)| e.user == user)
+                    .map(|(k, e)| (*k, e.clone())),
+            );
+        }
+        v
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+}
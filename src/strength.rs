@@ -0,0 +1,96 @@
+//! Estimation of the strength of passwords, independent of any database.
+
+/// Rough strength of a password, as estimated by [`password_strength`].
+/// Levels are ordered, so that a minimum can be required:
+/// ```
+/// use safe_box::{password_strength, Strength};
+///
+/// assert!(password_strength("aaaaaaaa") < Strength::Fair);
+/// assert!(password_strength("correct horse battery staple") >= Strength::Strong);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Strength {
+    /// Less than 28 bits of entropy, guessed in seconds.
+    VeryWeak,
+    /// Less than 36 bits of entropy.
+    Weak,
+    /// Less than 60 bits of entropy, enough against online guessing.
+    Fair,
+    /// Less than 128 bits of entropy.
+    Strong,
+    /// At least 128 bits of entropy.
+    VeryStrong,
+}
+
+impl Strength {
+    fn from_bits(bits: f64) -> Self {
+        match bits {
+            b if b < 28.0 => Self::VeryWeak,
+            b if b < 36.0 => Self::Weak,
+            b if b < 60.0 => Self::Fair,
+            b if b < 128.0 => Self::Strong,
+            _ => Self::VeryStrong,
+        }
+    }
+}
+
+/// Estimate the entropy of `pw` in bits.
+///
+/// Each character counts as a pick among the classes of characters `pw` uses,
+/// except that repeating the previous character or following it in a sequence,
+/// like in `aaa` or `123`, counts as a single bit.
+/// Dictionary words are not recognized, so this overestimates passwords such as `Password1`.
+pub fn password_entropy(pw: &str) -> f64 {
+    let (mut lower, mut upper, mut digit, mut symbol, mut other) =
+        (false, false, false, false, false);
+    for c in pw.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            c if c.is_ascii() => symbol = true,
+            _ => other = true,
+        }
+    }
+    let pool = [
+        (lower, 26),
+        (upper, 26),
+        (digit, 10),
+        (symbol, 33),
+        (other, 100),
+    ]
+    .iter()
+    .filter(|(used, _)| *used)
+    .map(|(_, n)| n)
+    .sum::<u32>();
+    let per_char = f64::from(pool.max(1)).log2();
+    let mut prev: Option<char> = None;
+    let mut bits = 0.0;
+    for c in pw.chars() {
+        let predictable = prev.is_some_and(|p| (c as i64 - p as i64).abs() <= 1);
+        bits += if predictable { 1.0 } else { per_char };
+        prev = Some(c);
+    }
+    bits
+}
+
+/// Estimate the strength of `pw`, see [`password_entropy`].
+///
+/// This gives feedback to users choosing a password,
+/// and can back a policy set with [`SafeBox::with_password_policy`](crate::SafeBox::with_password_policy):
+/// ```
+/// use safe_box::{password_strength, SafeBox, Strength};
+///
+/// # fn example(safe: SafeBox) {
+/// let safe = safe.with_password_policy(|pw| {
+///     match password_strength(pw) {
+///         s if s >= Strength::Fair => Ok(()),
+///         _ => Err("password is too easy to guess".to_owned()),
+///     }
+/// });
+/// # }
+/// ```
+pub fn password_strength(pw: &str) -> Strength {
+    Strength::from_bits(password_entropy(pw))
+}
@@ -0,0 +1,151 @@
+//! Generation of the tokens issued by a [`SafeBox`](crate::SafeBox).
+
+use std::{convert::Infallible, fmt, str::FromStr};
+
+use base64::Engine;
+
+use crate::{hasher::Rng, Error};
+
+/// Default length of random tokens in bytes.
+///
+/// This stays at the 32 bytes tokens always had, rather than 64,
+/// so that existing clients and columns sized for 44 base64 characters keep working;
+/// 256 bits are already far beyond guessing.
+pub(crate) const TOKEN_LEN: usize = 32;
+
+/// Minimum length of random tokens in bytes, so that they hold at least 128 bits of entropy.
+pub(crate) const MIN_TOKEN_LEN: usize = 16;
+
+/// Only scope of the tokens issued by [`SafeBox::verify`](crate::SafeBox::verify)
+/// to users who must change their password.
+pub const CHANGE_PASSWORD_SCOPE: &str = "change_password";
+
+/// A token issued to a user, to be handed to the client and checked with
+/// [`SafeBox::verify_token`](crate::SafeBox::verify_token).
+///
+/// Methods taking tokens also accept `&str`, so that tokens sent back by clients can be checked as is.
+/// `Display` shows the whole token, while `Debug` shows it [`redact`]ed.
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Token(String);
+
+impl Token {
+    /// The token as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Take the token out as a string.
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl From<String> for Token {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Token> for String {
+    fn from(value: Token) -> Self {
+        value.0
+    }
+}
+
+impl FromStr for Token {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl AsRef<str> for Token {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Token").field(&redact(&self.0)).finish()
+    }
+}
+
+/// A source of fresh tokens, set with [`SafeBoxBuilder::token_gen`](crate::SafeBoxBuilder::token_gen).
+///
+/// Tokens must be unpredictable, as anyone knowing one is authenticated as its user.
+/// Only a digest of each token is stored, so their length does not matter to the database.
+/// Tokens must not start or end with whitespace, which [`SafeBox::verify_token`](crate::SafeBox::verify_token) ignores.
+/// Signed tokens do not use the generator.
+///
+/// Closures returning a `String` are generators too:
+/// ```
+/// # use safe_box::TokenGen;
+/// let gen = || format!("sk_live_{}", safe_box::RandomTokenGen.generate());
+/// assert!(gen.generate().starts_with("sk_live_"));
+/// ```
+pub trait TokenGen: Send + Sync {
+    /// Generate a new token.
+    fn generate(&self) -> String;
+}
+
+impl<F: Fn() -> String + Send + Sync> TokenGen for F {
+    fn generate(&self) -> String {
+        self()
+    }
+}
+
+/// The default generator, making tokens of 32 random bytes encoded in standard base64.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomTokenGen;
+
+impl TokenGen for RandomTokenGen {
+    fn generate(&self) -> String {
+        random_token(None, TOKEN_LEN)
+    }
+}
+
+/// A token of `len` random bytes like those of [`RandomTokenGen`], made with `rng` or the OS.
+pub(crate) fn random_token(rng: Option<&Rng>, len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    Rng::fill(rng, &mut buf);
+    base64::engine::general_purpose::STANDARD.encode(buf)
+}
+
+/// Check that random tokens of `len` bytes are long enough not to be guessed.
+pub(crate) fn check_token_len(len: usize) -> Result<(), Error> {
+    if len < MIN_TOKEN_LEN {
+        return Err(Error::InvalidConfig(format!(
+            "token length {len} is less than {MIN_TOKEN_LEN} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// Start of `token` that is safe to reveal,
+/// which is empty unless the token is long enough to keep most of it hidden.
+pub(crate) fn token_id(token: &str) -> String {
+    if token.chars().count() <= 8 {
+        return String::new();
+    }
+    token.chars().take(4).collect()
+}
+
+/// Redacted form of `token` that is safe to log, like `ab3F**`.
+/// At most its first 4 characters are kept, and none if it has 8 characters or less.
+/// ```
+/// assert_eq!(safe_box::redact("ab3Fjk9Lmn"), "ab3F**");
+/// assert_eq!(safe_box::redact("ab"), "**");
+/// ```
+pub fn redact(token: &str) -> String {
+    format!("{}**", token_id(token))
+}
@@ -0,0 +1,136 @@
+//! Second factor with time-based one-time passwords (TOTP, RFC 6238).
+//!
+//! Codes have 6 digits, change every 30 seconds and are computed with HMAC-SHA1,
+//! which is what authenticator apps expect by default.
+//! If a pepper is configured, secrets are stored encrypted with ChaCha20-Poly1305
+//! under a key derived from the pepper.
+
+use std::time::UNIX_EPOCH;
+
+use base64::Engine;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use getrandom::getrandom;
+use sqlx::{query, Row};
+use totp_lite::{totp_custom, Sha1};
+
+use crate::{
+    hasher::{secret, Secret},
+    Error, SafeBox,
+};
+
+/// Seconds during which a code is valid.
+const STEP: u64 = 30;
+
+/// Number of steps before and after the current one whose codes are also accepted,
+/// to tolerate clocks that are slightly off.
+const SKEW: u64 = 1;
+
+/// Label of the key derived from the pepper to encrypt secrets.
+const KEY_LABEL: &[u8] = b"safe-box totp secret";
+
+const NONCE_LEN: usize = 12;
+
+impl SafeBox {
+    /// Set the TOTP `secret` of `user`, replacing any previous one.
+    /// The secret is the raw bytes the authenticator app is given, usually encoded in base32.
+    /// Fail with [`Error::UserNotExist`] if `user` does not exist.
+    pub async fn set_totp(&self, user: &str, secret: &[u8]) -> Result<(), Error> {
+        let stored = self.seal(secret)?;
+        self.set_totp_column(user, Some(stored)).await
+    }
+
+    /// Remove the TOTP secret of `user`, turning the second factor off.
+    /// Fail with [`Error::UserNotExist`] if `user` does not exist.
+    pub async fn clear_totp(&self, user: &str) -> Result<(), Error> {
+        self.set_totp_column(user, None).await
+    }
+
+    /// Check the 6-digit TOTP `code` of `user`,
+    /// accepting the codes of the previous and next 30 seconds too.
+    /// Return `false` if the user has no TOTP secret.
+    /// Fail with [`Error::UserNotExist`] if `user` does not exist.
+    ///
+    /// This is independent of [`SafeBox::verify`], which should be called first.
+    /// Codes are not remembered, so a code can be used again while it is valid.
+    ///
+    /// Wrong codes count towards [`SafeBox::failed_attempts`] and the lockout policy,
+    /// see [`SafeBox::with_lockout`], apart from wrong passwords,
+    /// so that a right password does not give more tries at the code.
+    /// A locked user fails with [`Error::AccountLocked`] without the code being checked.
+    pub async fn verify_totp(&self, user: &str, code: &str) -> Result<bool, Error> {
+        let user = &*self.canon(user);
+        let now = self.clock.now();
+        if self.is_locked(user, now) {
+            return Err(Error::AccountLocked(user.to_owned()));
+        }
+        let q = format!(
+            r#"SELECT totp_secret FROM "{}" WHERE "user" = $1"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(user);
+        let row = q.fetch_optional(&self.pool).await?;
+        let row = row.ok_or_else(|| Error::UserNotExist(user.to_owned()))?;
+        let Some(stored) = row.try_get::<Option<&str>, _>("totp_secret")? else {
+            return Ok(false);
+        };
+        let key = self.unseal(stored)?;
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let ok = (0..=2 * SKEW).any(|i| {
+            let t = (secs + i * STEP).saturating_sub(SKEW * STEP);
+            totp_custom::<Sha1>(STEP, 6, &key, t) == code
+        });
+        self.record_in(&self.totp_failure, user, ok, now);
+        Ok(ok)
+    }
+
+    async fn set_totp_column(&self, user: &str, stored: Option<String>) -> Result<(), Error> {
+        self.writable()?;
+        let user = &*self.canon(user);
+        let q = format!(
+            r#"UPDATE "{}" SET totp_secret = $1 WHERE "user" = $2"#,
+            self.tables.main
+        );
+        let q = query(&q).bind(stored).bind(user);
+        if q.execute(&self.pool).await?.rows_affected() == 0 {
+            return Err(Error::UserNotExist(user.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Textual form of `secret` stored in the database,
+    /// which is base64 of either the secret or, with a pepper, the nonce and the ciphertext.
+    fn seal(&self, secret: &[u8]) -> Result<String, Error> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let Some(key) = self.hasher().derive_key(KEY_LABEL) else {
+            return Ok(engine.encode(secret));
+        };
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom(&mut nonce).unwrap();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..]));
+        let ct = cipher
+            .encrypt(Nonce::from_slice(&nonce), secret)
+            .map_err(|_| Error::InvalidConfig("failed to encrypt TOTP secret".to_owned()))?;
+        Ok(engine.encode([&nonce[..], &ct].concat()))
+    }
+
+    /// Inverse of [`SafeBox::seal`].
+    fn unseal(&self, stored: &str) -> Result<Secret<Vec<u8>>, Error> {
+        let bad = || Error::InvalidData("unusable TOTP secret".to_owned());
+        let v = base64::engine::general_purpose::STANDARD
+            .decode(stored)
+            .map_err(|_| bad())?;
+        let Some(key) = self.hasher().derive_key(KEY_LABEL) else {
+            return Ok(secret(v));
+        };
+        if v.len() < NONCE_LEN {
+            return Err(bad());
+        }
+        let (nonce, ct) = v.split_at(NONCE_LEN);
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key[..]));
+        let pt = cipher.decrypt(Nonce::from_slice(nonce), ct);
+        pt.map(secret).map_err(|_| bad())
+    }
+}
@@ -0,0 +1,91 @@
+use sqlx::Transaction;
+
+use crate::{
+    db::{Connection, Db},
+    Error, Event, SafeBox,
+};
+
+/// A transaction on the database of a [`SafeBox`], created by [`SafeBox::transaction`].
+/// Changes are applied by [`Tx::commit`], and rolled back if the `Tx` is dropped before.
+///
+/// While a transaction is open, other operations on the database may have to wait for it.
+pub struct Tx<'a> {
+    safe: &'a SafeBox,
+    tx: Transaction<'static, Db>,
+    /// Created users, reported once committed.
+    created: Vec<String>,
+    /// Deleted users, whose tokens are invalidated once committed.
+    deleted: Vec<String>,
+}
+
+impl<'a> Tx<'a> {
+    pub(crate) fn new(safe: &'a SafeBox, tx: Transaction<'static, Db>) -> Self {
+        Self {
+            safe,
+            tx,
+            created: vec![],
+            deleted: vec![],
+        }
+    }
+
+    /// Like [`SafeBox::create`], within this transaction.
+    pub async fn create(&mut self, user: &str, pass: &str) -> Result<(), Error> {
+        let user = &*self.safe.canon(user);
+        self.safe.validate_user(user)?;
+        self.safe.validate(pass.as_bytes())?;
+        let p = self.safe.hash(pass.as_bytes()).await?;
+        self.safe.insert(&mut *self.tx, user, &p).await?;
+        self.created.push(user.to_owned());
+        Ok(())
+    }
+
+    /// Like [`SafeBox::update`], within this transaction.
+    pub async fn update(&mut self, user: &str, pass: &str, new: &str) -> Result<(), Error> {
+        let user = &*self.safe.canon(user);
+        self.safe
+            .check_pass(&mut *self.tx, user, pass.as_bytes())
+            .await?;
+        self.safe.validate(new.as_bytes())?;
+        let p = self.safe.hash(new.as_bytes()).await?;
+        self.safe
+            .set_phc(&mut self.tx, user, &p, new.as_bytes())
+            .await
+    }
+
+    /// Like [`SafeBox::delete`], within this transaction.
+    pub async fn delete(&mut self, user: &str, pass: &str) -> Result<(), Error> {
+        let user = &*self.safe.canon(user);
+        self.safe
+            .check_pass(&mut *self.tx, user, pass.as_bytes())
+            .await?;
+        self.safe.remove(&mut self.tx, user).await?;
+        self.deleted.push(user.to_owned());
+        Ok(())
+    }
+
+    /// The connection of this transaction, to run other queries on it.
+    pub fn conn(&mut self) -> &mut Connection {
+        &mut self.tx
+    }
+
+    /// Commit the transaction.
+    pub async fn commit(self) -> Result<(), Error> {
+        self.tx.commit().await?;
+        self.safe
+            .count_users(self.created.len(), self.deleted.len());
+        for user in &self.created {
+            self.safe.emit(Event::Created { user });
+        }
+        for user in &self.deleted {
+            self.safe.emit(Event::Deleted { user });
+            self.safe.invalidate_user_token(user).await?;
+        }
+        Ok(())
+    }
+
+    /// Roll the transaction back, which dropping it also does.
+    pub async fn rollback(self) -> Result<(), Error> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+#![cfg(all(feature = "blocking", not(feature = "postgres")))]
+
+use safe_box::{blocking::BlockingSafeBox, Error, SafeBox};
+
+#[test]
+fn blocking_user_cnt() -> Result<(), Error> {
+    let safe = BlockingSafeBox::open(SafeBox::builder().cache_user_cnt().build_in_memory())?;
+    assert_eq!(safe.user_cnt()?, 0);
+    safe.create("alice", "password")?;
+    safe.create("bob", "password")?;
+    assert_eq!(safe.user_cnt_cached(), Some(2));
+    safe.delete_if_exists("bob")?;
+    assert_eq!(safe.user_cnt()?, 1);
+    assert_eq!(safe.user_cnt_cached(), Some(1));
+    let safe = BlockingSafeBox::open(SafeBox::new_in_memory())?;
+    assert_eq!(safe.user_cnt_cached(), None);
+    Ok(())
+}
@@ -0,0 +1,69 @@
+#![cfg(not(feature = "postgres"))]
+
+mod common;
+
+use std::time::Duration;
+
+use common::TestClock;
+use safe_box::{Error, SafeBox};
+
+const WINDOW: Duration = Duration::from_secs(60);
+const COOLDOWN: Duration = Duration::from_secs(300);
+
+async fn open(clock: &TestClock, window: Duration, cooldown: Duration) -> Result<SafeBox, Error> {
+    let safe = SafeBox::builder()
+        .clock(clock.clone())
+        .lockout(3, window, cooldown)
+        .build_in_memory()
+        .await?;
+    safe.create("alice", "password").await?;
+    Ok(safe)
+}
+
+#[tokio::test]
+async fn lockout_ends_after_cooldown() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open(&clock, WINDOW, COOLDOWN).await?;
+    for _ in 0..3 {
+        assert!(safe.verify("alice", "wrong").await?.is_none());
+    }
+    assert_eq!(safe.failed_attempts("alice"), 3);
+    let res = safe.verify("alice", "password").await;
+    assert!(matches!(res, Err(Error::AccountLocked(_))));
+    clock.advance(COOLDOWN - Duration::from_secs(1));
+    let res = safe.verify("alice", "password").await;
+    assert!(matches!(res, Err(Error::AccountLocked(_))));
+    clock.advance(Duration::from_secs(1));
+    assert!(safe.verify("alice", "password").await?.is_some());
+    assert_eq!(safe.failed_attempts("alice"), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn failures_outside_window_are_forgotten() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open(&clock, WINDOW, COOLDOWN).await?;
+    for _ in 0..2 {
+        assert!(safe.verify("alice", "wrong").await?.is_none());
+    }
+    clock.advance(WINDOW + Duration::from_secs(1));
+    for _ in 0..2 {
+        assert!(safe.verify("alice", "wrong").await?.is_none());
+    }
+    assert_eq!(safe.failed_attempts("alice"), 4);
+    assert!(safe.verify("alice", "password").await?.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unbounded_lockout_does_not_overflow() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open(&clock, Duration::MAX, Duration::MAX).await?;
+    for _ in 0..3 {
+        assert!(safe.verify("alice", "wrong").await?.is_none());
+    }
+    clock.advance(Duration::from_secs(100 * 365 * 24 * 3600));
+    let res = safe.verify("alice", "password").await;
+    assert!(matches!(res, Err(Error::AccountLocked(_))));
+    Ok(())
+}
@@ -0,0 +1,29 @@
+#![cfg(not(feature = "postgres"))]
+
+use safe_box::{Error, SafeBox};
+
+#[tokio::test]
+async fn reset_token_does_not_outlive_user() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    safe.create("bob", "old password").await?;
+    let token = safe.issue_reset_token("bob").await?;
+    assert!(safe.delete_if_exists("bob").await?);
+    safe.create("bob", "new password").await?;
+    let res = safe.reset_password(&token, "attacker").await;
+    assert!(matches!(res, Err(Error::BadToken(_))));
+    assert!(safe.verify("bob", "attacker").await?.is_none());
+    assert!(safe.verify("bob", "new password").await?.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reset_token_is_dropped_on_password_change() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    safe.create("bob", "old password").await?;
+    let token = safe.issue_reset_token("bob").await?;
+    safe.change_password("bob", "old password", "new password")
+        .await?;
+    let res = safe.reset_password(&token, "attacker").await;
+    assert!(matches!(res, Err(Error::BadToken(_))));
+    Ok(())
+}
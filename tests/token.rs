@@ -0,0 +1,174 @@
+#![cfg(not(feature = "postgres"))]
+
+mod common;
+
+use std::time::Duration;
+
+use common::TestClock;
+use safe_box::{Error, SafeBox};
+
+#[tokio::test]
+async fn issue_token_to_missing_user_fails() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    let res = safe.issue_token("never").await;
+    assert!(matches!(res, Err(Error::UserNotExist(_))));
+    assert_eq!(safe.token_count(), 0);
+    Ok(())
+}
+
+/// A database file in the temporary directory, removed with its side files when dropped.
+struct TempDb(std::path::PathBuf);
+
+impl TempDb {
+    fn new(name: &str) -> Self {
+        let p = std::env::temp_dir().join(format!("safe-box-{name}-{}.db", std::process::id()));
+        let db = Self(p);
+        db.remove();
+        db
+    }
+
+    fn remove(&self) {
+        for ext in ["", "-wal", "-shm"] {
+            let mut p = self.0.clone().into_os_string();
+            p.push(ext);
+            let _ = std::fs::remove_file(p);
+        }
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+#[tokio::test]
+async fn token_of_deleted_user_is_rejected() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    safe.create("alice", "password").await?;
+    let token = safe.verify("alice", "password").await?.unwrap();
+    safe.delete("alice", "password").await?;
+    assert!(matches!(safe.verify_token(&token), Err(Error::BadToken(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn persistent_token_of_deleted_user_is_not_restored() -> Result<(), Error> {
+    let db = TempDb::new("deleted-token");
+    let token = {
+        let safe = SafeBox::builder().persistent_tokens().build(&db.0).await?;
+        safe.create("alice", "password").await?;
+        let token = safe.verify("alice", "password").await?.unwrap();
+        assert!(safe.delete_if_exists("alice").await?);
+        assert!(safe.verify_token(&token).is_err());
+        safe.close().await?;
+        token
+    };
+    let safe = SafeBox::builder().persistent_tokens().build(&db.0).await?;
+    assert!(safe.verify_token(&token).is_err());
+    assert_eq!(safe.token_count(), 0);
+    safe.close().await
+}
+
+#[tokio::test]
+async fn whitespace_around_token_is_ignored() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    safe.create("alice", "password").await?;
+    let token = safe.issue_token("alice").await?;
+    assert_eq!(safe.verify_token(format!(" {token} "))?, "alice");
+    assert_eq!(safe.verify_token(format!("\t{token}\r\n"))?, "alice");
+    assert!(safe.touch_token(format!(" {token} ")).await?);
+    safe.invalidate_token(format!(" {token} ")).await?;
+    assert!(safe.verify_token(&token).is_err());
+    assert!(!safe.touch_token(&token).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn short_custom_token_does_not_panic() -> Result<(), Error> {
+    let safe = SafeBox::builder()
+        .token_gen(|| "ab".to_owned())
+        .build_in_memory()
+        .await?;
+    safe.create("alice", "password").await?;
+    let token = safe.issue_token("alice").await?;
+    assert_eq!(format!("{token:?}"), r#"Token("**")"#);
+    assert_eq!(safe.active_tokens("alice")[0].id, "");
+    safe.invalidate_token(&token).await?;
+    assert!(safe.verify_token(&token).is_err());
+    safe.expire_token().await
+}
+
+#[tokio::test]
+async fn too_long_token_lifetime_is_refused() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    safe.create("alice", "password").await?;
+    let res = safe.issue_token_ttl("alice", Duration::MAX).await;
+    assert!(matches!(res, Err(Error::InvalidConfig(_))));
+    assert_eq!(safe.token_count(), 0);
+    Ok(())
+}
+
+const TTL: Duration = Duration::from_secs(60);
+
+async fn open_with_clock(clock: &TestClock) -> Result<SafeBox, Error> {
+    let safe = SafeBox::builder()
+        .clock(clock.clone())
+        .token_ttl(TTL)
+        .reset_token_ttl(TTL)
+        .build_in_memory()
+        .await?;
+    safe.create("alice", "password").await?;
+    Ok(safe)
+}
+
+#[tokio::test]
+async fn token_expires_at_its_lifetime() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open_with_clock(&clock).await?;
+    let token = safe.issue_token("alice").await?;
+    clock.advance(TTL - Duration::from_millis(1));
+    assert_eq!(safe.verify_token(&token)?, "alice");
+    safe.expire_token().await?;
+    assert_eq!(safe.token_count(), 1);
+    clock.advance(Duration::from_millis(1));
+    safe.expire_token().await?;
+    assert_eq!(safe.token_count(), 0);
+    assert!(matches!(safe.verify_token(&token), Err(Error::BadToken(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn touch_token_extends_lifetime() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open_with_clock(&clock).await?;
+    let token = safe.issue_token("alice").await?;
+    clock.advance(TTL / 2);
+    assert!(safe.touch_token(&token).await?);
+    clock.advance(TTL / 2);
+    safe.expire_token().await?;
+    assert_eq!(safe.verify_token(&token)?, "alice");
+    clock.advance(TTL / 2);
+    assert!(safe.verify_token(&token).is_err());
+    assert!(!safe.touch_token(&token).await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn expire_reset_token_removes_expired_ones() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open_with_clock(&clock).await?;
+    let old = safe.issue_reset_token("alice").await?;
+    clock.advance(TTL / 2);
+    let new = safe.issue_reset_token("alice").await?;
+    safe.expire_reset_token();
+    assert_eq!(safe.reset_token_count(), 2);
+    clock.advance(TTL / 2);
+    safe.expire_reset_token();
+    assert_eq!(safe.reset_token_count(), 1);
+    let res = safe.reset_password(&old, "new password").await;
+    assert!(matches!(res, Err(Error::BadToken(_))));
+    safe.reset_password(&new, "new password").await?;
+    assert_eq!(safe.reset_token_count(), 0);
+    Ok(())
+}
@@ -0,0 +1,52 @@
+#![cfg(all(feature = "totp", not(feature = "postgres")))]
+
+mod common;
+
+use std::time::Duration;
+
+use common::TestClock;
+use safe_box::{Error, SafeBox};
+
+const COOLDOWN: Duration = Duration::from_secs(300);
+
+async fn open(clock: &TestClock) -> Result<SafeBox, Error> {
+    let safe = SafeBox::builder()
+        .clock(clock.clone())
+        .lockout(3, Duration::from_secs(60), COOLDOWN)
+        .build_in_memory()
+        .await?;
+    safe.create("alice", "password").await?;
+    safe.set_totp("alice", b"12345678901234567890").await?;
+    Ok(safe)
+}
+
+#[tokio::test]
+async fn wrong_codes_lock_out() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open(&clock).await?;
+    for code in ["000000", "111111", "222222"] {
+        assert!(!safe.verify_totp("alice", code).await?);
+    }
+    assert_eq!(safe.failed_attempts("alice"), 3);
+    let res = safe.verify_totp("alice", "333333").await;
+    assert!(matches!(res, Err(Error::AccountLocked(_))));
+    let res = safe.verify("alice", "password").await;
+    assert!(matches!(res, Err(Error::AccountLocked(_))));
+    clock.advance(COOLDOWN);
+    assert!(safe.verify("alice", "password").await?.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn right_password_does_not_reset_wrong_codes() -> Result<(), Error> {
+    let clock = TestClock::new();
+    let safe = open(&clock).await?;
+    for code in ["000000", "111111"] {
+        assert!(!safe.verify_totp("alice", code).await?);
+    }
+    assert!(safe.verify("alice", "password").await?.is_some());
+    assert!(!safe.verify_totp("alice", "222222").await?);
+    let res = safe.verify_totp("alice", "333333").await;
+    assert!(matches!(res, Err(Error::AccountLocked(_))));
+    Ok(())
+}
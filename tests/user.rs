@@ -0,0 +1,142 @@
+#![cfg(not(feature = "postgres"))]
+
+mod common;
+
+use common::TestClock;
+use safe_box::{Error, SafeBox, VerifyOutcome};
+
+#[tokio::test]
+async fn update_of_missing_user_fails() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    let res = safe.update("bob", "password", "new password").await;
+    assert!(matches!(res, Err(Error::UserNotExist(u)) if u == "bob"));
+    let res = safe.update_if("bob", "$argon2id$", "new password").await;
+    assert!(matches!(res, Err(Error::UserNotExist(u)) if u == "bob"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn delete_of_missing_user_fails() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    let res = safe.delete("bob", "password").await;
+    assert!(matches!(res, Err(Error::UserNotExist(u)) if u == "bob"));
+    assert!(!safe.delete_if_exists("bob").await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deleted_user_cannot_be_updated() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    safe.create("bob", "password").await?;
+    safe.delete("bob", "password").await?;
+    let res = safe.update("bob", "password", "new password").await;
+    assert!(matches!(res, Err(Error::UserNotExist(_))));
+    let res = safe.delete("bob", "password").await;
+    assert!(matches!(res, Err(Error::UserNotExist(_))));
+    Ok(())
+}
+
+#[tokio::test]
+async fn verify_many_hides_missing_users() -> Result<(), Error> {
+    let safe = SafeBox::builder()
+        .hide_missing_users()
+        .build_in_memory()
+        .await?;
+    safe.create("alice", "password").await?;
+    let creds = [
+        ("alice".to_owned(), "password".to_owned()),
+        ("alice".to_owned(), "wrong".to_owned()),
+        ("bob".to_owned(), "password".to_owned()),
+    ];
+    let outcomes = safe.verify_many(&creds).await?;
+    assert_eq!(
+        outcomes,
+        [
+            VerifyOutcome::Authenticated,
+            VerifyOutcome::WrongPassword,
+            VerifyOutcome::WrongPassword,
+        ]
+    );
+    let outcomes = SafeBox::new_in_memory().await?.verify_many(&creds).await?;
+    assert_eq!(outcomes[2], VerifyOutcome::UserNotFound);
+    Ok(())
+}
+
+#[tokio::test]
+async fn keyword_table_names_are_quoted() -> Result<(), Error> {
+    for table in ["order", "Select", "user"] {
+        let safe = SafeBox::builder()
+            .table(table)
+            .password_history(1)
+            .persistent_tokens()
+            .build_in_memory()
+            .await?;
+        safe.create("bob", "password").await?;
+        safe.update("bob", "password", "new password").await?;
+        safe.set_meta("bob", "team", "red").await?;
+        assert_eq!(safe.find_by_meta("team", "red").await?, ["bob"]);
+        let token = safe.verify("bob", "new password").await?.unwrap();
+        safe.rename("bob", "carol").await?;
+        safe.delete("carol", "new password").await?;
+        assert!(safe.verify_token(&token).is_err());
+        assert_eq!(safe.user_cnt().await?, 0);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn table_names_of_other_stores_are_refused() {
+    for table in [
+        "meta",
+        "TOKEN",
+        "password_history",
+        "schema_version",
+        "MAIN",
+        "admins_meta",
+    ] {
+        let res = SafeBox::builder().table(table).build_in_memory().await;
+        assert!(matches!(res, Err(Error::InvalidConfig(_))), "{table}");
+    }
+}
+
+#[tokio::test]
+async fn password_history_within_one_millisecond() -> Result<(), Error> {
+    let safe = SafeBox::builder()
+        .clock(TestClock::new())
+        .password_history(2)
+        .build_in_memory()
+        .await?;
+    safe.create("alice", "p1").await?;
+    safe.update("alice", "p1", "p2").await?;
+    safe.update("alice", "p2", "p3").await?;
+    safe.update("alice", "p3", "p4").await?;
+    let res = safe.update("alice", "p4", "p3").await;
+    assert!(matches!(res, Err(Error::PasswordReused(_))));
+    safe.update("alice", "p4", "p1").await?;
+    Ok(())
+}
+
+/// The tests link a plain SQLite, which would ignore the key.
+#[cfg(feature = "sqlcipher")]
+#[tokio::test]
+async fn database_key_without_sqlcipher_is_refused() {
+    let res = SafeBox::builder()
+        .database_key("secret")
+        .build_in_memory()
+        .await;
+    assert!(matches!(res, Err(Error::InvalidConfig(_))));
+}
+
+#[tokio::test]
+async fn wrong_password_is_not_in_error() -> Result<(), Error> {
+    let safe = SafeBox::new_in_memory().await?;
+    safe.create("alice", "password").await?;
+    let e = safe
+        .update("alice", "hunter2", "new password")
+        .await
+        .unwrap_err();
+    assert!(matches!(&e, Error::BadPass { user } if user == "alice"));
+    assert_eq!(e.to_string(), "invalid password for user 'alice'");
+    assert!(!format!("{e:?}").contains("hunter2"));
+    Ok(())
+}